@@ -0,0 +1,100 @@
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{Data, http::{Request, Response}};
+
+fn ids(ids: &str) -> Vec<u64> {
+    ids.split(';').filter_map(|id| id.parse().ok()).collect()
+}
+
+/// The wrapper every response comes in. Filters are ignored; items always have every field.
+fn wrap<T: Serialize>(data: &mut Data, items: &[T], has_more: bool) -> Response {
+    data.quota_remaining = data.quota_remaining.saturating_sub(1);
+
+    Response::json(200, &json!({
+        "items": items,
+        "has_more": has_more,
+        "quota_remaining": data.quota_remaining,
+        "quota_max": 10000
+    }))
+}
+
+/// Newest first, one page of them.
+fn page<T: Clone>(request: &Request, mut items: Vec<T>, created: impl Fn(&T) -> u64) -> (Vec<T>, bool) {
+    let page = request.query.get("page").and_then(|page| page.parse::<usize>().ok()).unwrap_or(1).max(1);
+    let page_size = request.query.get("pagesize").and_then(|size| size.parse::<usize>().ok()).unwrap_or(30);
+
+    items.sort_by_key(|item| std::cmp::Reverse(created(item)));
+
+    let start = (page - 1) * page_size;
+
+    (items.iter().skip(start).take(page_size).cloned().collect(), items.len() > start + page_size)
+}
+
+pub fn handle(data: &mut Data, request: &Request, path: &[&str]) -> Response {
+    if data.quota_remaining == 0 {
+        return Response::json(400, &json!({
+            "error_id": 502,
+            "error_name": "throttle_violation",
+            "error_message": "out of quota"
+        }));
+    }
+
+    let site = request.query.get("site").cloned().unwrap_or_default();
+
+    match path {
+        ["filters", "create"] => wrap(data, &[json!({ "filter": "mock" })], false),
+        ["sites"] => {
+            let (sites, has_more) = page(request, data.sites.clone(), |_| 0);
+
+            wrap(data, &sites, has_more)
+        }
+        ["questions"] => {
+            let tagged = request.query.get("tagged");
+
+            let questions = data.questions.iter()
+                .filter(|question| question.site == site && tagged.is_none_or(|tag| question.tags.contains(tag)))
+                .cloned()
+                .collect();
+
+            let (questions, has_more) = page(request, questions, |question| question.creation_date);
+
+            wrap(data, &questions, has_more)
+        }
+        ["questions", question_ids] => {
+            let question_ids = ids(question_ids);
+
+            let questions = data.questions.iter().filter(|question| question.site == site && question_ids.contains(&question.question_id)).cloned().collect::<Vec<_>>();
+
+            wrap(data, &questions, false)
+        }
+        ["questions", question_id, "answers"] => {
+            let question_id = question_id.parse::<u64>().unwrap_or_default();
+
+            let answers = data.answers.iter().filter(|answer| answer.site == site && answer.question_id == question_id).cloned().collect();
+
+            let (answers, has_more) = page(request, answers, |answer| answer.creation_date);
+
+            wrap(data, &answers, has_more)
+        }
+        ["answers", answer_ids] => {
+            let answer_ids = ids(answer_ids);
+
+            let answers = data.answers.iter().filter(|answer| answer.site == site && answer_ids.contains(&answer.answer_id)).cloned().collect::<Vec<_>>();
+
+            wrap(data, &answers, false)
+        }
+        ["comments", comment_ids] => {
+            let comment_ids = ids(comment_ids);
+
+            let comments = data.comments.iter().filter(|comment| comment.site == site && comment_ids.contains(&comment.comment_id)).cloned().collect::<Vec<_>>();
+
+            wrap(data, &comments, false)
+        }
+        _ => Response::json(404, &json!({
+            "error_id": 404,
+            "error_name": "no_method",
+            "error_message": "no such method"
+        }))
+    }
+}
@@ -0,0 +1,186 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::{WebSocketStream, tungstenite::protocol::Message};
+
+use crate::{ChatClient, ChatEvent, Data, Posted, State, http::{Request, Response}, login::FKEY};
+
+const BOT_USER_NAME: &str = "bot";
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Chat renders bare links as anchors, which is what the bot looks for to tell what's already been posted.
+fn render(text: &str) -> String {
+    text.split(' ').map(|word| match word.starts_with("http://") || word.starts_with("https://") {
+        true => format!(r#"<a href="{}">{}</a>"#, word, word),
+        false => word.to_owned()
+    }).collect::<Vec<String>>().join(" ")
+}
+
+/// Records an event in its room and sends it to every connection there.
+fn push(data: &mut Data, mut event: ChatEvent) -> ChatEvent {
+    event.id = data.next_event_id;
+    data.next_event_id += 1;
+
+    let frame = json!({ format!("r{}", event.room_id): { "e": [&event], "t": event.id } }).to_string();
+
+    for client in data.chat_clients.values().filter(|client| client.room_id == event.room_id) {
+        let _ = client.frames.send(frame.clone());
+    }
+
+    data.events.entry(event.room_id).or_default().push(event.clone());
+
+    event
+}
+
+pub fn say(data: &mut Data, room_id: u64, user_id: u64, user_name: &str, content: &str) -> u64 {
+    let message_id = data.next_event_id;
+
+    push(data, ChatEvent {
+        event_type: 1,
+        time_stamp: now(),
+        id: 0,
+        room_id,
+        user_id,
+        user_name: user_name.to_owned(),
+        message_id: Some(message_id),
+        content: Some(content.to_owned())
+    });
+
+    message_id
+}
+
+pub fn mention(data: &mut Data, room_id: u64, user_id: u64, user_name: &str, content: &str) -> u64 {
+    let message_id = say(data, room_id, user_id, user_name, content);
+
+    push(data, ChatEvent {
+        event_type: 8,
+        time_stamp: now(),
+        id: 0,
+        room_id,
+        user_id,
+        user_name: user_name.to_owned(),
+        message_id: Some(message_id),
+        content: Some(content.to_owned())
+    });
+
+    data.unacked.entry(room_id).or_default().push(message_id);
+
+    message_id
+}
+
+/// Chat's HTTP endpoints. Nothing checks the fkey or cookies.
+pub fn handle(data: &mut Data, address: SocketAddr, request: &Request, path: &[&str]) -> Response {
+    let form = request.form();
+
+    match (request.method.as_str(), path) {
+        ("GET", ["chats", "join", "favorite"]) => Response::html(format!(
+            r#"<html><body><a href="/users/{}/{}">{}</a><input type="hidden" name="fkey" value="{}"></body></html>"#,
+            data.bot_user_id, BOT_USER_NAME, BOT_USER_NAME, FKEY
+        )),
+        ("POST", ["ws-auth"]) => match form.get("roomid").and_then(|room_id| room_id.parse::<u64>().ok()) {
+            Some(room_id) => Response::json(200, &json!({ "url": format!("ws://{}/chat-ws/{}", address, room_id) })),
+            None => Response::text(400, "missing roomid")
+        },
+        ("POST", ["chats", room_id, "events"]) => {
+            let room_id = room_id.parse::<u64>().unwrap_or_default();
+            let since = form.get("since").and_then(|since| since.parse::<u64>().ok()).unwrap_or(0);
+            let count = form.get("msgCount").and_then(|count| count.parse::<usize>().ok()).unwrap_or(100);
+
+            let room = data.events.get(&room_id).cloned().unwrap_or_default();
+
+            let events = match form.get("mode").map(String::as_str) {
+                Some("Events") => room.into_iter().filter(|event| event.id > since).collect::<Vec<ChatEvent>>(),
+                _ => {
+                    let messages = room.into_iter().filter(|event| event.event_type == 1).collect::<Vec<ChatEvent>>();
+
+                    messages[messages.len().saturating_sub(count)..].to_vec()
+                }
+            };
+
+            Response::json(200, &json!({ "time": data.next_event_id - 1, "events": events }))
+        }
+        ("POST", ["chats", room_id, "messages", "new"]) => {
+            if let Some(cooldown) = data.cooldowns.pop_front() {
+                return Response::text(409, format!("You can perform this action again in {} seconds", cooldown));
+            }
+
+            let room_id = room_id.parse::<u64>().unwrap_or_default();
+            let text = form.get("text").cloned().unwrap_or_default();
+
+            let bot_user_id = data.bot_user_id;
+            let message_id = say(data, room_id, bot_user_id, BOT_USER_NAME, &render(&text));
+
+            data.posted.push(Posted {
+                room_id,
+                message_id,
+                text
+            });
+
+            Response::json(200, &json!({ "id": message_id, "time": now() }))
+        }
+        ("POST", ["messages", "ack"]) => {
+            let message_id = form.get("id").and_then(|id| id.parse::<u64>().ok()).unwrap_or_default();
+
+            data.acked.push(message_id);
+
+            for unacked in data.unacked.values_mut() {
+                unacked.retain(|id| *id != message_id);
+            }
+
+            Response::text(200, "ok")
+        }
+        // Just enough of the room page for the bot to find unacked mentions in the `StartChat` call
+        ("GET", ["rooms", room_id, ..]) => {
+            let room_id = room_id.parse::<u64>().unwrap_or_default();
+
+            let unacked = data.unacked.get(&room_id).cloned().unwrap_or_default();
+            let unacked = unacked.iter().map(|id| format!("{}:1", id)).collect::<Vec<String>>().join(",");
+
+            Response::html(format!("<html><body><script>\nvar chat = StartChat({}, {},\n{{{}}});\n</script></body></html>", room_id, data.bot_user_id, unacked))
+        }
+        _ => Response::not_found()
+    }
+}
+
+/// A room's websocket. Frames are only ever sent; anything the client sends is ignored.
+pub async fn websocket(mut ws_stream: WebSocketStream<TcpStream>, room_id: u64, state: Arc<State>) {
+    let (frames, mut outbound) = mpsc::unbounded_channel();
+
+    let id = state.with(|data| {
+        let id = data.next_client_id;
+
+        data.next_client_id += 1;
+        data.chat_clients.insert(id, ChatClient {
+            room_id,
+            frames
+        });
+
+        id
+    });
+
+    loop {
+        tokio::select! {
+            frame = outbound.recv() => match frame {
+                Some(frame) => {
+                    if ws_stream.send(Message::Text(frame)).await.is_err() {
+                        break;
+                    }
+                }
+                None => break
+            },
+            message = ws_stream.next() => match message {
+                Some(Ok(_)) => (),
+                Some(Err(_)) | None => break
+            }
+        }
+    }
+
+    state.with(|data| data.chat_clients.remove(&id));
+}
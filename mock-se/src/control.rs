@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+use crate::{State, Site, Question, Answer, Comment, chat, sockets, http::{Request, Response}};
+
+#[derive(Deserialize)]
+struct SocketFrame {
+    action: String,
+    data: serde_json::Value
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChatMessage {
+    user_id: u64,
+    user_name: String,
+    content: String,
+    #[serde(default)]
+    mention: bool
+}
+
+fn body<T: DeserializeOwned>(request: &Request) -> Result<T, Response> {
+    serde_json::from_slice(&request.body).map_err(|err| Response::json(400, &json!({ "error": err.to_string() })))
+}
+
+/// `/_mock/…`, for scripting the mock from outside the process:
+///
+/// - `POST /_mock/sites`, `/_mock/questions`, `/_mock/answers`, `/_mock/comments` with the item as JSON
+/// - `POST /_mock/socket` with `{"action": …, "data": …}`, and `POST /_mock/socket/heartbeat`
+/// - `POST /_mock/rooms/{id}/messages` with `{"userId": …, "userName": …, "content": …, "mention": false}`
+/// - `POST /_mock/cooldowns` with a list of seconds
+/// - `GET /_mock/posted`, `GET /_mock/acked`, `GET /_mock/requests`
+pub fn handle(state: &Arc<State>, request: &Request, path: &[&str]) -> Response {
+    let result = match (request.method.as_str(), path) {
+        ("POST", ["sites"]) => body::<Site>(request).map(|site| state.with(|data| data.sites.push(site))).map(|_| json!({})),
+        ("POST", ["questions"]) => body::<Question>(request).map(|question| state.with(|data| data.questions.push(question))).map(|_| json!({})),
+        ("POST", ["answers"]) => body::<Answer>(request).map(|answer| state.with(|data| data.answers.push(answer))).map(|_| json!({})),
+        ("POST", ["comments"]) => body::<Comment>(request).map(|comment| state.with(|data| data.comments.push(comment))).map(|_| json!({})),
+        ("POST", ["socket"]) => body::<SocketFrame>(request).map(|frame| {
+            json!({ "delivered": state.with(|data| sockets::send(data, &frame.action, &frame.data.to_string())) })
+        }),
+        ("POST", ["socket", "heartbeat"]) => Ok(json!({ "delivered": state.with(sockets::heartbeat) })),
+        ("POST", ["rooms", room_id, "messages"]) => match room_id.parse::<u64>() {
+            Ok(room_id) => body::<ChatMessage>(request).map(|message| {
+                let message_id = state.with(|data| match message.mention {
+                    true => chat::mention(data, room_id, message.user_id, &message.user_name, &message.content),
+                    false => chat::say(data, room_id, message.user_id, &message.user_name, &message.content)
+                });
+
+                json!({ "messageId": message_id })
+            }),
+            Err(_) => Err(Response::text(400, "bad room ID"))
+        },
+        ("POST", ["cooldowns"]) => body::<Vec<u64>>(request).map(|cooldowns| state.with(|data| data.cooldowns.extend(cooldowns))).map(|_| json!({})),
+        ("GET", ["posted"]) => Ok(json!(state.data.lock().unwrap().posted)),
+        ("GET", ["acked"]) => Ok(json!(state.data.lock().unwrap().acked)),
+        ("GET", ["requests"]) => Ok(json!(state.data.lock().unwrap().requests)),
+        _ => Err(Response::not_found())
+    };
+
+    match result {
+        Ok(body) => Response::json(200, &body),
+        Err(response) => response
+    }
+}
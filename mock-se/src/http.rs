@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::io;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_tungstenite::{WebSocketStream, tungstenite::{handshake::derive_accept_key, protocol::Role}};
+
+// Nothing the bot sends comes close
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    // Lowercased names
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>
+}
+
+impl Request {
+    /// The body as a urlencoded form, which is how the bot posts to chat and the login pages.
+    pub fn form(&self) -> HashMap<String, String> {
+        url::form_urlencoded::parse(&self.body).into_owned().collect()
+    }
+
+    pub fn is_websocket(&self) -> bool {
+        self.headers.get("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+pub struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String
+}
+
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into()
+        }
+    }
+
+    pub fn html(body: impl Into<String>) -> Response {
+        Response {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: body.into()
+        }
+    }
+
+    pub fn json(status: u16, body: &impl Serialize) -> Response {
+        Response {
+            status,
+            content_type: "application/json; charset=utf-8",
+            body: serde_json::to_string(body).unwrap()
+        }
+    }
+
+    pub fn not_found() -> Response {
+        Response::text(404, "not found")
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            409 => "Conflict",
+            _ => "Error"
+        }
+    }
+
+    pub async fn write(&self, stream: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        let head = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", self.status, self.reason(), self.content_type, self.body.len());
+
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(self.body.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+fn bad_request(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_owned())
+}
+
+pub async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Request> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+
+    let header_end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+
+        let read = stream.read(&mut chunk).await?;
+
+        if read == 0 || buffer.len() + read > MAX_REQUEST_SIZE {
+            return Err(bad_request("incomplete or oversized headers"));
+        }
+
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).into_owned();
+    let mut lines = head.lines();
+
+    let (method, target) = match lines.next().unwrap_or_default().split(' ').collect::<Vec<&str>>()[..] {
+        [method, target, _] => (method.to_owned(), target.to_owned()),
+        _ => return Err(bad_request("malformed request line"))
+    };
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+        .collect::<HashMap<String, String>>();
+
+    let content_length = headers.get("content-length").map_or(Ok(0), |length| length.parse::<usize>()).map_err(|_| bad_request("bad content-length"))?;
+
+    if content_length > MAX_REQUEST_SIZE {
+        return Err(bad_request("oversized body"));
+    }
+
+    let mut body = buffer.split_off(header_end + 4);
+
+    while body.len() < content_length {
+        let read = stream.read(&mut chunk).await?;
+
+        if read == 0 {
+            return Err(bad_request("incomplete body"));
+        }
+
+        body.extend_from_slice(&chunk[..read]);
+    }
+
+    body.truncate(content_length);
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+
+    Ok(Request {
+        method,
+        path: path.to_owned(),
+        query: url::form_urlencoded::parse(query.as_bytes()).into_owned().collect(),
+        headers,
+        body
+    })
+}
+
+/// Completes the websocket handshake for `request`, which has already been read off `stream`.
+pub async fn upgrade<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: &Request) -> io::Result<WebSocketStream<S>> {
+    let key = request.headers.get("sec-websocket-key").ok_or_else(|| bad_request("missing Sec-WebSocket-Key"))?;
+
+    let head = format!("HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n\r\n", derive_accept_key(key.as_bytes()));
+
+    stream.write_all(head.as_bytes()).await?;
+
+    Ok(WebSocketStream::from_raw_socket(stream, Role::Server, None).await)
+}
@@ -0,0 +1,390 @@
+//! A stand-in for the parts of Stack Exchange the bot talks to, so it can be run end to end without a network:
+//!
+//! - the login pages (`/users/login…`)
+//! - chat (`/chats/join/favorite`, `/ws-auth`, `/chats/{room}/events`, `/chats/{room}/messages/new`, `/messages/ack`,
+//!   `/rooms/{room}` and the room websockets)
+//! - qa.sockets, as a websocket at `/`
+//! - the API, under `/2.3`
+//!
+//! Everything is served from one address, so each base URL the bot is configured with points at `MockSe::url`. Tests
+//! script it through the methods on `MockSe`; the `mock-se` binary exposes the same through `/_mock/…` endpoints.
+
+mod http;
+mod api;
+mod chat;
+mod login;
+mod sockets;
+mod control;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+
+use crate::http::{Request, Response};
+
+/// A site, as `/sites` lists it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Site {
+    pub api_site_parameter: String,
+    pub name: String,
+    pub site_url: String
+}
+
+/// A post's author, as the API embeds it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Owner {
+    pub user_id: u64,
+    pub display_name: String,
+    pub reputation: u64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Question {
+    /// The API site parameter, e.g. `codegolf`
+    #[serde(skip_serializing)]
+    pub site: String,
+    pub question_id: u64,
+    /// Seconds since the epoch
+    pub creation_date: u64,
+    pub title: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub owner: Option<Owner>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Answer {
+    #[serde(skip_serializing)]
+    pub site: String,
+    pub answer_id: u64,
+    pub question_id: u64,
+    pub creation_date: u64,
+    #[serde(default)]
+    pub owner: Option<Owner>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Comment {
+    #[serde(skip_serializing)]
+    pub site: String,
+    pub comment_id: u64,
+    pub post_id: u64,
+    pub creation_date: u64,
+    #[serde(default)]
+    pub owner: Option<Owner>
+}
+
+/// A chat event, as `/chats/{room}/events` and the room websockets send them.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatEvent {
+    /// 1 for a new message, 8 for a mention, 18 for a reply
+    pub event_type: u8,
+    pub time_stamp: u64,
+    pub id: u64,
+    pub room_id: u64,
+    pub user_id: u64,
+    pub user_name: String,
+    pub message_id: Option<u64>,
+    pub content: Option<String>
+}
+
+/// A message the bot posted through `messages/new`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Posted {
+    pub room_id: u64,
+    pub message_id: u64,
+    pub text: String
+}
+
+struct SocketClient {
+    actions: HashSet<String>,
+    frames: mpsc::UnboundedSender<String>
+}
+
+struct ChatClient {
+    room_id: u64,
+    frames: mpsc::UnboundedSender<String>
+}
+
+struct Data {
+    sites: Vec<Site>,
+    questions: Vec<Question>,
+    answers: Vec<Answer>,
+    comments: Vec<Comment>,
+    quota_remaining: u64,
+
+    // Event IDs double as chat's event times
+    next_event_id: u64,
+    events: HashMap<u64, Vec<ChatEvent>>,
+    posted: Vec<Posted>,
+    acked: Vec<u64>,
+    // Mentions not acked yet, per room, which the room page lists
+    unacked: HashMap<u64, Vec<u64>>,
+    // Answered to `messages/new` in turn before anything is posted
+    cooldowns: VecDeque<u64>,
+
+    bot_user_id: u64,
+    reject_logins: bool,
+
+    next_client_id: u64,
+    socket_clients: HashMap<u64, SocketClient>,
+    chat_clients: HashMap<u64, ChatClient>,
+
+    requests: Vec<String>
+}
+
+struct State {
+    data: Mutex<Data>,
+    // Woken on every change, for the `wait_for_…` methods
+    changed: Notify,
+    address: SocketAddr
+}
+
+impl State {
+    fn with<T>(&self, f: impl FnOnce(&mut Data) -> T) -> T {
+        let result = f(&mut self.data.lock().unwrap());
+
+        self.changed.notify_waiters();
+
+        result
+    }
+
+    /// Waits until `f` returns something, or `timeout` passes.
+    async fn wait<T>(&self, timeout: Duration, f: impl Fn(&Data) -> Option<T>) -> Option<T> {
+        tokio::time::timeout(timeout, async {
+            loop {
+                let changed = self.changed.notified();
+
+                if let Some(result) = f(&self.data.lock().unwrap()) {
+                    return result;
+                }
+
+                changed.await;
+            }
+        }).await.ok()
+    }
+}
+
+/// A running mock. The server stops when this is dropped.
+pub struct MockSe {
+    state: Arc<State>,
+    server: tokio::task::JoinHandle<()>
+}
+
+impl MockSe {
+    /// Starts a mock on a free port on localhost.
+    pub async fn start() -> io::Result<MockSe> {
+        MockSe::bind("127.0.0.1:0").await
+    }
+
+    pub async fn bind(address: &str) -> io::Result<MockSe> {
+        let listener = TcpListener::bind(address).await?;
+
+        let state = Arc::new(State {
+            data: Mutex::new(Data {
+                sites: Vec::new(),
+                questions: Vec::new(),
+                answers: Vec::new(),
+                comments: Vec::new(),
+                quota_remaining: 10000,
+                next_event_id: 1,
+                events: HashMap::new(),
+                posted: Vec::new(),
+                acked: Vec::new(),
+                unacked: HashMap::new(),
+                cooldowns: VecDeque::new(),
+                bot_user_id: 1,
+                reject_logins: false,
+                next_client_id: 0,
+                socket_clients: HashMap::new(),
+                chat_clients: HashMap::new(),
+                requests: Vec::new()
+            }),
+            changed: Notify::new(),
+            address: listener.local_addr()?
+        });
+
+        let server = tokio::spawn(serve(listener, Arc::clone(&state)));
+
+        Ok(MockSe {
+            state,
+            server
+        })
+    }
+
+    /// The base URL for every HTTP endpoint, e.g. `http://127.0.0.1:41234`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.state.address)
+    }
+
+    /// The base URL for the websockets, e.g. `ws://127.0.0.1:41234`.
+    pub fn ws_url(&self) -> String {
+        format!("ws://{}", self.state.address)
+    }
+
+    pub fn add_site(&self, site: Site) {
+        self.state.with(|data| data.sites.push(site));
+    }
+
+    /// Puts a question on the API. It isn't announced on qa.sockets; see `socket_send`.
+    pub fn add_question(&self, question: Question) {
+        self.state.with(|data| data.questions.push(question));
+    }
+
+    pub fn add_answer(&self, answer: Answer) {
+        self.state.with(|data| data.answers.push(answer));
+    }
+
+    pub fn add_comment(&self, comment: Comment) {
+        self.state.with(|data| data.comments.push(comment));
+    }
+
+    pub fn set_quota_remaining(&self, quota_remaining: u64) {
+        self.state.with(|data| data.quota_remaining = quota_remaining);
+    }
+
+    /// Sends a frame to every qa.sockets connection subscribed to `action`. `data` is encoded as a string, the way
+    /// qa.sockets does. Returns how many connections it went to.
+    pub fn socket_send(&self, action: &str, data: &serde_json::Value) -> usize {
+        self.state.with(|state| sockets::send(state, action, &data.to_string()))
+    }
+
+    /// Sends a heartbeat to every qa.sockets connection.
+    pub fn socket_heartbeat(&self) -> usize {
+        self.state.with(sockets::heartbeat)
+    }
+
+    /// Closes every qa.sockets connection, as a server restart would.
+    pub fn socket_drop_all(&self) {
+        self.state.with(|data| data.socket_clients.clear());
+    }
+
+    /// Waits for at least `count` qa.sockets connections to subscribe to `action`.
+    pub async fn wait_for_subscribers(&self, action: &str, count: usize, timeout: Duration) -> bool {
+        self.state.wait(timeout, |data| (sockets::subscribers(data, action) >= count).then_some(())).await.is_some()
+    }
+
+    /// Waits for at least `count` websocket connections to a chat room.
+    pub async fn wait_for_chat_connections(&self, room_id: u64, count: usize, timeout: Duration) -> bool {
+        self.state.wait(timeout, |data| (data.chat_clients.values().filter(|client| client.room_id == room_id).count() >= count).then_some(())).await.is_some()
+    }
+
+    /// Posts `content` (HTML, as chat renders it) to a room as someone other than the bot. Returns the message ID.
+    pub fn chat_say(&self, room_id: u64, user_id: u64, user_name: &str, content: &str) -> u64 {
+        self.state.with(|data| chat::say(data, room_id, user_id, user_name, content))
+    }
+
+    /// Mentions the bot in a room, leaving the mention unacked until the bot acks it. Returns the message ID.
+    pub fn chat_mention(&self, room_id: u64, user_id: u64, user_name: &str, content: &str) -> u64 {
+        self.state.with(|data| chat::mention(data, room_id, user_id, user_name, content))
+    }
+
+    /// Answers the next `messages/new` calls with a cooldown of each of `seconds` in turn.
+    pub fn chat_cooldowns(&self, seconds: &[u64]) {
+        self.state.with(|data| data.cooldowns.extend(seconds));
+    }
+
+    /// Makes the login pages reject every attempt.
+    pub fn reject_logins(&self, reject: bool) {
+        self.state.with(|data| data.reject_logins = reject);
+    }
+
+    /// Everything the bot has posted, in order.
+    pub fn posted(&self) -> Vec<Posted> {
+        self.state.data.lock().unwrap().posted.clone()
+    }
+
+    /// Waits for the bot to have posted at least `count` messages to the room, and returns them all. Whatever's been
+    /// posted is returned if `timeout` passes first.
+    pub async fn wait_for_posts(&self, room_id: u64, count: usize, timeout: Duration) -> Vec<String> {
+        let posts = |data: &Data| data.posted.iter().filter(|posted| posted.room_id == room_id).map(|posted| posted.text.clone()).collect::<Vec<String>>();
+
+        match self.state.wait(timeout, |data| Some(posts(data)).filter(|posts| posts.len() >= count)).await {
+            Some(posts) => posts,
+            None => posts(&self.state.data.lock().unwrap())
+        }
+    }
+
+    /// Message IDs the bot has acked, in order.
+    pub fn acked(&self) -> Vec<u64> {
+        self.state.data.lock().unwrap().acked.clone()
+    }
+
+    /// Waits for the bot to have acked `message_id`, and returns whether it did before `timeout` passed.
+    pub async fn wait_for_ack(&self, message_id: u64, timeout: Duration) -> bool {
+        self.state.wait(timeout, |data| data.acked.contains(&message_id).then_some(())).await.is_some()
+    }
+
+    /// Every request so far, as `METHOD /path`, including websocket upgrades.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.data.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for MockSe {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn serve(listener: TcpListener, state: Arc<State>) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(_) => continue
+        };
+
+        tokio::spawn(handle_connection(stream, Arc::clone(&state)));
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<State>) {
+    let request = match http::read_request(&mut stream).await {
+        Ok(request) => request,
+        Err(err) => {
+            let _ = Response::text(400, err.to_string()).write(&mut stream).await;
+
+            return;
+        }
+    };
+
+    state.with(|data| data.requests.push(format!("{} {}", request.method, request.path)));
+
+    if request.is_websocket() {
+        let path = request.path.clone();
+
+        let ws_stream = match http::upgrade(stream, &request).await {
+            Ok(ws_stream) => ws_stream,
+            Err(_) => return
+        };
+
+        match path.strip_prefix("/chat-ws/").and_then(|room_id| room_id.parse::<u64>().ok()) {
+            Some(room_id) => chat::websocket(ws_stream, room_id, state).await,
+            None if path == "/" => sockets::websocket(ws_stream, state).await,
+            None => ()
+        }
+
+        return;
+    }
+
+    let response = route(&state, &request);
+
+    let _ = response.write(&mut stream).await;
+}
+
+fn route(state: &Arc<State>, request: &Request) -> Response {
+    let path = request.path.trim_end_matches('/').split('/').skip(1).collect::<Vec<&str>>();
+
+    match (request.method.as_str(), &path[..]) {
+        (_, ["2.3", rest @ ..]) => state.with(|data| api::handle(data, request, rest)),
+        (_, ["_mock", rest @ ..]) => control::handle(state, request, rest),
+        (_, ["users", ..]) => state.with(|data| login::handle(data, request, &path[1..])),
+        _ => state.with(|data| chat::handle(data, state.address, request, &path))
+    }
+}
@@ -0,0 +1,17 @@
+use crate::{Data, http::{Request, Response}};
+
+// Checked by the real site, but any value will do here
+pub const FKEY: &str = "mockfkey";
+
+/// `/users/login` and friends. Any email and password are accepted, unless logins are being rejected.
+pub fn handle(data: &mut Data, request: &Request, path: &[&str]) -> Response {
+    match (request.method.as_str(), path) {
+        ("GET", ["login"]) => Response::html(format!(r#"<html><body><form><input type="hidden" name="fkey" value="{}"></form></body></html>"#, FKEY)),
+        ("POST", ["login-or-signup", "validation", "track"]) => match data.reject_logins {
+            true => Response::text(200, "Login-Failed"),
+            false => Response::text(200, "Login-OK")
+        },
+        ("POST", ["login"]) => Response::html(r#"<html><body><a href="/users/logout">log out</a></body></html>"#),
+        _ => Response::not_found()
+    }
+}
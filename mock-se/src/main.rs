@@ -0,0 +1,15 @@
+use mock_se::MockSe;
+
+/// Runs the mock on the address given as the only argument, `127.0.0.1:9186` by default, until interrupted.
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let address = std::env::args().nth(1).unwrap_or_else(|| "127.0.0.1:9186".to_owned());
+
+    let mock = MockSe::bind(&address).await?;
+
+    println!("serving on {} and {}; script it through {}/_mock", mock.url(), mock.ws_url(), mock.url());
+
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
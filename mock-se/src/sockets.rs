@@ -0,0 +1,74 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::{WebSocketStream, tungstenite::protocol::Message};
+
+use crate::{Data, SocketClient, State};
+
+pub fn send(data: &mut Data, action: &str, payload: &str) -> usize {
+    let frame = json!({ "action": action, "data": payload }).to_string();
+
+    data.socket_clients.values()
+        .filter(|client| client.actions.contains(action))
+        .filter(|client| client.frames.send(frame.clone()).is_ok())
+        .count()
+}
+
+pub fn heartbeat(data: &mut Data) -> usize {
+    let frame = json!({ "action": "hb", "data": "hb" }).to_string();
+
+    data.socket_clients.values().filter(|client| client.frames.send(frame.clone()).is_ok()).count()
+}
+
+pub fn subscribers(data: &Data, action: &str) -> usize {
+    data.socket_clients.values().filter(|client| client.actions.contains(action)).count()
+}
+
+/// A qa.sockets connection: every text message from the client subscribes it to that action, except `pong`.
+pub async fn websocket(mut ws_stream: WebSocketStream<TcpStream>, state: Arc<State>) {
+    let (frames, mut outbound) = mpsc::unbounded_channel();
+
+    let id = state.with(|data| {
+        let id = data.next_client_id;
+
+        data.next_client_id += 1;
+        data.socket_clients.insert(id, SocketClient {
+            actions: HashSet::new(),
+            frames
+        });
+
+        id
+    });
+
+    loop {
+        tokio::select! {
+            frame = outbound.recv() => match frame {
+                Some(frame) => {
+                    if ws_stream.send(Message::Text(frame)).await.is_err() {
+                        break;
+                    }
+                }
+                // Dropped by the test
+                None => {
+                    let _ = ws_stream.close(None).await;
+
+                    break;
+                }
+            },
+            message = ws_stream.next() => match message {
+                Some(Ok(Message::Text(action))) if action != "pong" => state.with(|data| {
+                    if let Some(client) = data.socket_clients.get_mut(&id) {
+                        client.actions.insert(action);
+                    }
+                }),
+                Some(Ok(_)) => (),
+                Some(Err(_)) | None => break
+            }
+        }
+    }
+
+    state.with(|data| data.socket_clients.remove(&id));
+}
@@ -0,0 +1,153 @@
+use std::time::Duration;
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use mock_se::{MockSe, Question, Owner};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn next_text<S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin>(stream: &mut S) -> Value {
+    loop {
+        match tokio::time::timeout(TIMEOUT, stream.next()).await.expect("timed out waiting for a frame") {
+            Some(Ok(Message::Text(text))) => return serde_json::from_str(&text).unwrap(),
+            Some(Ok(_)) => continue,
+            other => panic!("socket ended: {:?}", other)
+        }
+    }
+}
+
+fn question(site: &str, question_id: u64, creation_date: u64, reputation: u64) -> Question {
+    Question {
+        site: site.to_owned(),
+        question_id,
+        creation_date,
+        title: format!("Question {}", question_id),
+        tags: vec!["code-golf".to_owned()],
+        owner: Some(Owner {
+            user_id: 7,
+            display_name: "someone".to_owned(),
+            reputation
+        })
+    }
+}
+
+#[tokio::test]
+async fn login_accepts_anything_unless_rejecting() {
+    let mock = MockSe::start().await.unwrap();
+    let client = reqwest::Client::new();
+
+    let page = client.get(format!("{}/users/login", mock.url())).send().await.unwrap().text().await.unwrap();
+
+    assert!(page.contains(r#"name="fkey""#));
+
+    let track = format!("{}/users/login-or-signup/validation/track", mock.url());
+
+    assert_eq!(client.post(&track).form(&[("email", "a@b.c")]).send().await.unwrap().text().await.unwrap(), "Login-OK");
+
+    mock.reject_logins(true);
+
+    assert_ne!(client.post(&track).form(&[("email", "a@b.c")]).send().await.unwrap().text().await.unwrap(), "Login-OK");
+}
+
+#[tokio::test]
+async fn api_pages_newest_questions_and_looks_up_ids() {
+    let mock = MockSe::start().await.unwrap();
+    let client = reqwest::Client::new();
+
+    mock.add_question(question("codegolf", 1, 100, 1));
+    mock.add_question(question("codegolf", 2, 300, 50));
+    mock.add_question(question("codegolf", 3, 200, 50));
+    mock.add_question(question("codegolf.meta", 4, 400, 50));
+
+    let newest: Value = client.get(format!("{}/2.3/questions?site=codegolf&page=1&pagesize=2", mock.url())).send().await.unwrap().json().await.unwrap();
+
+    let ids = newest["items"].as_array().unwrap().iter().map(|item| item["question_id"].as_u64().unwrap()).collect::<Vec<u64>>();
+
+    assert_eq!(ids, [2, 3]);
+    assert_eq!(newest["has_more"], true);
+
+    let lookup: Value = client.get(format!("{}/2.3/questions/1;4?site=codegolf", mock.url())).send().await.unwrap().json().await.unwrap();
+
+    assert_eq!(lookup["items"].as_array().unwrap().len(), 1);
+    assert_eq!(lookup["items"][0]["owner"]["reputation"], 1);
+    assert!(lookup["quota_remaining"].as_u64().unwrap() < newest["quota_remaining"].as_u64().unwrap());
+}
+
+#[tokio::test]
+async fn socket_frames_only_go_to_subscribers() {
+    let mock = MockSe::start().await.unwrap();
+
+    let (mut subscribed, _) = tokio_tungstenite::connect_async(format!("{}/", mock.ws_url())).await.unwrap();
+    let (mut other, _) = tokio_tungstenite::connect_async(format!("{}/", mock.ws_url())).await.unwrap();
+
+    subscribed.send(Message::Text("200-questions-newest".to_owned())).await.unwrap();
+    other.send(Message::Text("202-questions-newest".to_owned())).await.unwrap();
+
+    assert!(mock.wait_for_subscribers("200-questions-newest", 1, TIMEOUT).await);
+    assert!(mock.wait_for_subscribers("202-questions-newest", 1, TIMEOUT).await);
+
+    assert_eq!(mock.socket_send("200-questions-newest", &json!({ "id": "5" })), 1);
+
+    let frame = next_text(&mut subscribed).await;
+
+    assert_eq!(frame["action"], "200-questions-newest");
+    assert_eq!(serde_json::from_str::<Value>(frame["data"].as_str().unwrap()).unwrap()["id"], "5");
+
+    assert_eq!(mock.socket_heartbeat(), 2);
+    assert_eq!(next_text(&mut other).await["action"], "hb");
+}
+
+#[tokio::test]
+async fn chat_posts_are_recorded_and_broadcast() {
+    let mock = MockSe::start().await.unwrap();
+    let client = reqwest::Client::new();
+
+    let ws_auth: Value = client.post(format!("{}/ws-auth", mock.url())).form(&[("roomid", "240"), ("fkey", "x")]).send().await.unwrap().json().await.unwrap();
+
+    let (mut room, _) = tokio_tungstenite::connect_async(format!("{}?l=0", ws_auth["url"].as_str().unwrap())).await.unwrap();
+
+    // The connection is only registered once the handshake is through on the mock's side
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    mock.chat_cooldowns(&[3]);
+
+    let new = format!("{}/chats/240/messages/new", mock.url());
+
+    let throttled = client.post(&new).form(&[("text", "https://codegolf.stackexchange.com/q/1"), ("fkey", "x")]).send().await.unwrap();
+
+    assert_eq!(throttled.status(), 409);
+    assert_eq!(throttled.text().await.unwrap(), "You can perform this action again in 3 seconds");
+
+    assert!(client.post(&new).form(&[("text", "https://codegolf.stackexchange.com/q/1"), ("fkey", "x")]).send().await.unwrap().status().is_success());
+
+    assert_eq!(mock.wait_for_posts(240, 1, TIMEOUT).await, ["https://codegolf.stackexchange.com/q/1"]);
+
+    let frame = next_text(&mut room).await;
+
+    assert_eq!(frame["r240"]["e"][0]["event_type"], 1);
+    assert!(frame["r240"]["e"][0]["content"].as_str().unwrap().contains(r#"<a href="https://codegolf.stackexchange.com/q/1">"#));
+
+    let history: Value = client.post(format!("{}/chats/240/events", mock.url())).form(&[("since", "0"), ("mode", "Messages"), ("msgCount", "100")]).send().await.unwrap().json().await.unwrap();
+
+    assert_eq!(history["events"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn mentions_stay_on_the_room_page_until_acked() {
+    let mock = MockSe::start().await.unwrap();
+    let client = reqwest::Client::new();
+
+    let message_id = mock.chat_mention(240, 7, "someone", "@bot hi");
+
+    let page = client.get(format!("{}/rooms/240", mock.url())).send().await.unwrap().text().await.unwrap();
+
+    assert!(page.contains(&format!("{{{}:1}}", message_id)));
+
+    client.post(format!("{}/messages/ack", mock.url())).form(&[("id", message_id.to_string()), ("fkey", "x".to_owned())]).send().await.unwrap();
+
+    let page = client.get(format!("{}/rooms/240", mock.url())).send().await.unwrap().text().await.unwrap();
+
+    assert!(page.contains("\n{});"));
+    assert_eq!(mock.acked(), [message_id]);
+}
@@ -0,0 +1,250 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::collections::{HashMap, BTreeMap};
+use tokio::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use tracing::{info, instrument};
+
+use crate::{time, Ids, Config, http::{self, Request, Response}, shutdown::Shutdown, login::User, queue::MessageQueue, supervisor::Supervisor, watch::{Watcher, Source}};
+
+// How far back a reconciliation triggered without `sinceMs` looks
+const DEFAULT_RECONCILE_SINCE: u128 = 3600000;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RouteStatus<'a> {
+    id: &'a str,
+    site: &'a str,
+    room_id: u64,
+    user: &'a str,
+    watch_socket: &'a str,
+    paused: bool,
+    dry_run: bool,
+    queue_depth: usize,
+    first_deliveries: BTreeMap<Source, u64>
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct KnownIds {
+    room_id: u64,
+    site: String,
+    count: usize
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewMessage {
+    user: String,
+    text: String
+}
+
+/// Everything the admin API can look at or change.
+pub struct Admin {
+    config: Arc<Config>,
+    users: HashMap<String, Arc<User>>,
+    ids: Arc<Mutex<Ids>>,
+    queue: Arc<MessageQueue>,
+    watcher: Arc<Watcher>,
+    supervisor: Arc<Supervisor>
+}
+
+impl Admin {
+    pub fn new(config: Arc<Config>, users: HashMap<String, Arc<User>>, ids: Arc<Mutex<Ids>>, queue: Arc<MessageQueue>, watcher: Arc<Watcher>, supervisor: Arc<Supervisor>) -> Arc<Admin> {
+        Arc::new(Admin {
+            config,
+            users,
+            ids,
+            queue,
+            watcher,
+            supervisor
+        })
+    }
+
+    async fn routes(&self) -> Response {
+        let first_deliveries = self.watcher.first_deliveries().await;
+
+        let mut routes = Vec::new();
+
+        for route in self.config.get_route_configs().into_values() {
+            routes.push(RouteStatus {
+                id: route.id,
+                site: &route.site.id,
+                room_id: route.room.room_id(),
+                user: route.user_id,
+                watch_socket: route.watch_socket_id,
+                paused: self.watcher.is_paused(route.id).await,
+                dry_run: route.dry_run,
+                queue_depth: self.queue.depth(route.room.room_id()).await,
+                first_deliveries: first_deliveries.get(route.id).cloned().unwrap_or_default()
+            });
+        }
+
+        routes.sort_by_key(|route| route.id);
+
+        Response::json(200, &routes)
+    }
+
+    async fn route_action(&self, route_id: &str, action: &str, request: &Request) -> Response {
+        if self.config.get_route_config(route_id).is_none() {
+            return Response::error(404, "no such route");
+        }
+
+        match action {
+            "pause" | "resume" => {
+                let paused = action == "pause";
+                let was_paused = self.watcher.set_paused(route_id, paused).await;
+
+                info!(route = route_id, paused, "pause state changed on request");
+
+                Response::json(200, &serde_json::json!({ "paused": paused, "wasPaused": was_paused }))
+            }
+            "reconcile" => {
+                let since = match request.query.get("sinceMs").map(|since| since.parse::<u128>()) {
+                    Some(Ok(since)) => since,
+                    Some(Err(_)) => return Response::error(400, "`sinceMs` must be a number"),
+                    None => DEFAULT_RECONCILE_SINCE
+                };
+
+                let now = time();
+
+                match self.watcher.reconcile(route_id, now.saturating_sub(since), now, false).await {
+                    Ok(Some(missed)) => {
+                        info!(route = route_id, missed = missed.len(), "reconciled on request");
+
+                        Response::json(200, &serde_json::json!({ "missed": missed }))
+                    }
+                    Ok(None) => Response::error(400, "route can't be reconciled"),
+                    Err(err) => Response::error(500, &err.to_string())
+                }
+            }
+            _ => Response::error(404, "unknown route action")
+        }
+    }
+
+    async fn post_message(&self, room_id: &str, body: &[u8]) -> Response {
+        let room_id = match room_id.parse::<u64>() {
+            Ok(room_id) => room_id,
+            Err(_) => return Response::error(400, "bad room ID")
+        };
+
+        let message = match serde_json::from_slice::<NewMessage>(body) {
+            Ok(message) => message,
+            Err(err) => return Response::error(400, &err.to_string())
+        };
+
+        if !self.users.contains_key(&message.user) {
+            return Response::error(400, "no such logged-in user");
+        }
+
+        info!(room = room_id, user = %message.user, text = %message.text, "posting on request");
+
+        self.queue.push(room_id, &message.user, None, message.text, self.config.get_dry_run()).await;
+
+        Response::json(200, &serde_json::json!({ "queued": true }))
+    }
+
+    async fn known_ids(&self, method: &str, path: &[&str]) -> Response {
+        let mut ids = self.ids.lock().await;
+
+        let room_id = match path.first().map(|room_id| room_id.parse::<u64>()) {
+            Some(Ok(room_id)) => Some(room_id),
+            Some(Err(_)) => return Response::error(400, "bad room ID"),
+            None => None
+        };
+
+        match (method, room_id, path.get(1), path.get(2)) {
+            ("GET", None, None, None) => Response::json(200, &ids.counts().into_iter().map(|((room_id, site), count)| KnownIds {
+                room_id,
+                site,
+                count
+            }).collect::<Vec<KnownIds>>()),
+            ("GET", Some(room_id), Some(site), None) => Response::json(200, &ids.get(room_id, site)),
+            ("PUT", Some(room_id), Some(site), Some(post_id)) => {
+                let added = ids.insert(room_id, site, post_id);
+
+                info!(room = room_id, %site, %post_id, added, "known ID added on request");
+
+                Response::json(200, &serde_json::json!({ "added": added }))
+            }
+            ("DELETE", Some(room_id), Some(site), Some(post_id)) => {
+                let removed = ids.remove(room_id, site, post_id);
+
+                info!(room = room_id, %site, %post_id, removed, "known ID removed on request");
+
+                Response::json(200, &serde_json::json!({ "removed": removed }))
+            }
+            _ => Response::error(404, "not found")
+        }
+    }
+
+    /// Turns away requests that don't come from a client meant to use the API, such as a browser tricked into sending
+    /// one by a web page: the `Host` has to be the address being listened on, the token has to match, and anything
+    /// that changes state has to be sent as JSON, which a page can't do without a CORS preflight.
+    fn check(&self, request: &Request) -> Option<Response> {
+        let config = self.config.get_admin().unwrap();
+
+        // Clients of a unix socket make up their own `Host`, and web pages can't reach it anyway
+        if !config.listen.starts_with("unix:") && request.header("Host") != Some(config.listen.as_str()) {
+            return Some(Response::error(403, "unexpected `Host`"));
+        }
+
+        if request.header("Authorization").and_then(|value| value.strip_prefix("Bearer ")) != Some(config.token.as_str()) {
+            return Some(Response::error(401, "missing or wrong token"));
+        }
+
+        let json = request.header("Content-Type").is_some_and(|value| value.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("application/json"));
+
+        if matches!(request.method.as_str(), "POST" | "PUT" | "DELETE") && !json {
+            return Some(Response::error(415, "`Content-Type` must be `application/json`"));
+        }
+
+        None
+    }
+
+    async fn handle(&self, request: Request) -> Response {
+        if let Some(response) = self.check(&request) {
+            return response;
+        }
+
+        let path = request.path.trim_matches('/').split('/').collect::<Vec<&str>>();
+
+        match (request.method.as_str(), &path[..]) {
+            ("GET", ["routes"]) => self.routes().await,
+            ("POST", ["routes", route_id, action]) => self.route_action(route_id, action, &request).await,
+            ("GET", ["tasks"]) => Response::json(200, &self.supervisor.health().await),
+            ("GET", ["queue"]) => Response::json(200, &self.queue.depths().await),
+            ("POST", ["rooms", room_id, "messages"]) => self.post_message(room_id, &request.body).await,
+            (method, ["ids", rest @ ..]) => self.known_ids(method, rest).await,
+            ("GET", ["config"]) => Response::json(200, self.config.get_effective()),
+            _ => Response::error(404, "not found")
+        }
+    }
+}
+
+/// Serves the admin API. Every request needs `Authorization: Bearer {admin.token}`, and a `Host` of `admin.listen`
+/// unless that's a unix socket; `POST`, `PUT` and `DELETE` requests also need `Content-Type: application/json`.
+///
+/// - `GET /routes`: each route, whether it's paused, how many messages its room has queued and which sources delivered
+///   its posts first
+/// - `POST /routes/{id}/pause`, `POST /routes/{id}/resume`
+/// - `POST /routes/{id}/reconcile?sinceMs=…`: announces anything missed in that window, an hour by default
+/// - `GET /tasks`: the supervisor's view of every task
+/// - `GET /queue`: how many messages each room has waiting
+/// - `POST /rooms/{id}/messages` with `{"user": …, "text": …}`: queues a message as-is
+/// - `GET /ids`, `GET /ids/{room}/{site}`, `PUT /ids/{room}/{site}/{post}`, `DELETE /ids/{room}/{site}/{post}`
+/// - `GET /config`: the effective config, without secrets
+#[instrument(name = "admin", skip_all)]
+pub async fn serve(admin: Arc<Admin>, shutdown: Shutdown) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let listen = admin.config.get_admin().unwrap().listen.clone();
+
+    let listener = http::bind(&listen).await?;
+
+    info!(%listen, "serving the admin API");
+
+    http::serve(listener, &shutdown, move |request| {
+        let admin = Arc::clone(&admin);
+
+        async move { admin.handle(request).await }
+    }).await
+}
@@ -0,0 +1,451 @@
+use std::{error::Error, fmt};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use serde::{Deserialize, de::DeserializeOwned};
+use tracing::warn;
+
+use crate::{metrics, replay, capture::{self, Event}, config::ApiConfig};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+// Included in every filter, so callers can always see quota and backoff
+const WRAPPER_FIELDS: &[&str] = &[".items", ".has_more", ".quota_remaining", ".quota_max", ".backoff", ".error_id", ".error_name", ".error_message"];
+
+// How long every call is paused after a throttle violation that doesn't say when to come back
+const DEFAULT_THROTTLE_PAUSE: Duration = Duration::from_secs(60);
+
+/// Whether a call can be skipped when quota runs low.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Needed to announce posts
+    Essential,
+    Optional
+}
+
+/// An error reported by the API itself, e.g. `throttle_violation` or `no_method`.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub error_id: Option<u64>,
+    pub error_name: Option<String>,
+    pub error_message: String
+}
+
+impl Error for ApiError {}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "API returned {} ({}): {}", self.status, self.error_name.as_deref().unwrap_or("no error name"), self.error_message)
+    }
+}
+
+/// A non-essential call that wasn't made because quota is low.
+#[derive(Debug)]
+pub struct Shed {
+    method: &'static str,
+    quota_remaining: u64
+}
+
+impl Error for Shed {}
+
+impl fmt::Display for Shed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "skipped {} with {} quota left", self.method, self.quota_remaining)
+    }
+}
+
+#[derive(Debug)]
+struct MissingFilter {}
+
+impl Error for MissingFilter {}
+
+impl fmt::Display for MissingFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "filters/create returned no filter")
+    }
+}
+
+/// The common wrapper around every API response.
+#[derive(Debug, Deserialize)]
+pub struct Wrapper<T> {
+    #[serde(default = "Vec::new")]
+    pub items: Vec<T>,
+    #[serde(default)]
+    pub has_more: bool,
+    pub quota_remaining: Option<u64>,
+    pub quota_max: Option<u64>,
+    /// Seconds to wait before calling the same method again
+    pub backoff: Option<u64>,
+    pub error_id: Option<u64>,
+    pub error_name: Option<String>,
+    pub error_message: Option<String>
+}
+
+/// A response item, along with the fields to ask for when requesting it.
+pub trait ApiType: DeserializeOwned {
+    const FIELDS: &'static [&'static str];
+}
+
+// Fields that are requested but not read yet are kept so callers can use them without changing the filters
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShallowUser {
+    pub user_id: Option<u64>,
+    pub display_name: Option<String>,
+    pub reputation: Option<u64>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Question {
+    pub question_id: u64,
+    pub creation_date: u64,
+    pub title: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub owner: Option<ShallowUser>
+}
+
+impl ApiType for Question {
+    const FIELDS: &'static [&'static str] = &["question.question_id", "question.creation_date", "question.title", "question.tags", "question.owner", "shallow_user.user_id", "shallow_user.display_name", "shallow_user.reputation"];
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Answer {
+    pub answer_id: u64,
+    pub question_id: u64,
+    pub creation_date: u64,
+    pub owner: Option<ShallowUser>
+}
+
+impl ApiType for Answer {
+    const FIELDS: &'static [&'static str] = &["answer.answer_id", "answer.question_id", "answer.creation_date", "answer.owner", "shallow_user.user_id", "shallow_user.display_name", "shallow_user.reputation"];
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Comment {
+    pub comment_id: u64,
+    pub post_id: u64,
+    pub creation_date: u64,
+    pub owner: Option<ShallowUser>
+}
+
+impl ApiType for Comment {
+    const FIELDS: &'static [&'static str] = &["comment.comment_id", "comment.post_id", "comment.creation_date", "comment.owner", "shallow_user.user_id", "shallow_user.display_name", "shallow_user.reputation"];
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    pub user_id: u64,
+    pub display_name: String,
+    pub reputation: u64,
+    pub creation_date: u64
+}
+
+impl ApiType for User {
+    const FIELDS: &'static [&'static str] = &["user.user_id", "user.display_name", "user.reputation", "user.creation_date"];
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Site {
+    pub api_site_parameter: String,
+    pub name: String,
+    pub site_url: String
+}
+
+impl ApiType for Site {
+    const FIELDS: &'static [&'static str] = &["site.api_site_parameter", "site.name", "site.site_url"];
+}
+
+#[derive(Deserialize)]
+struct CreatedFilter {
+    filter: String
+}
+
+fn join_ids<T: ToString>(ids: &[T]) -> String {
+    ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(";")
+}
+
+struct Quota {
+    remaining: Option<u64>,
+    max: Option<u64>,
+    warned: bool
+}
+
+/// Spaces out every API call, honours per-method `backoff` and throttle violations, and keeps track of the day's quota.
+struct Governor {
+    interval: Duration,
+    warn_quota: u64,
+    shed_quota: u64,
+    // Earliest time the next call may be made, whatever its method
+    next_slot: Mutex<Instant>,
+    // Method -> earliest time it may be called again
+    backoffs: Mutex<HashMap<&'static str, Instant>>,
+    quota: Mutex<Quota>
+}
+
+impl Governor {
+    fn new(config: &ApiConfig) -> Governor {
+        Governor {
+            interval: Duration::from_secs_f64(1.0 / config.max_requests_per_second),
+            warn_quota: config.warn_quota,
+            shed_quota: config.shed_quota,
+            next_slot: Mutex::new(Instant::now()),
+            backoffs: Mutex::new(HashMap::new()),
+            quota: Mutex::new(Quota {
+                remaining: None,
+                max: None,
+                warned: false
+            })
+        }
+    }
+
+    /// Waits until `method` may be called, or refuses if it's optional and quota is low.
+    async fn admit(&self, method: &'static str, priority: Priority) -> Result<()> {
+        if priority == Priority::Optional {
+            if let Some(remaining) = self.quota.lock().await.remaining.filter(|remaining| *remaining < self.shed_quota) {
+                return Err(Box::new(Shed {
+                    method,
+                    quota_remaining: remaining
+                }));
+            }
+        }
+
+        let backoff = self.backoffs.lock().await.get(method).copied();
+
+        if let Some(until) = backoff {
+            tokio::time::sleep_until(until).await;
+        }
+
+        let slot = {
+            let mut next_slot = self.next_slot.lock().await;
+
+            let slot = (*next_slot).max(Instant::now());
+
+            *next_slot = slot + self.interval;
+
+            slot
+        };
+
+        tokio::time::sleep_until(slot).await;
+
+        Ok(())
+    }
+
+    async fn record<T>(&self, method: &'static str, result: &Result<Wrapper<T>>) {
+        match result {
+            Ok(wrapper) => {
+                if let Some(backoff) = wrapper.backoff {
+                    warn!(method, backoff, "backoff requested");
+
+                    self.backoffs.lock().await.insert(method, Instant::now() + Duration::from_secs(backoff));
+                }
+
+                let mut quota = self.quota.lock().await;
+
+                if let Some(remaining) = wrapper.quota_remaining {
+                    metrics::set("npsp_api_quota_remaining", &[], remaining as f64);
+
+                    quota.remaining = wrapper.quota_remaining;
+                    quota.max = wrapper.quota_max.or(quota.max);
+                }
+
+                match quota.remaining {
+                    Some(remaining) if remaining < self.warn_quota && !quota.warned => {
+                        warn!(remaining, max = quota.max, "quota low");
+
+                        quota.warned = true;
+                    }
+                    // Quota resets daily
+                    Some(remaining) if remaining >= self.warn_quota => quota.warned = false,
+                    _ => ()
+                }
+            }
+            Err(err) => {
+                if let Some(err) = err.downcast_ref::<ApiError>().filter(|err| err.error_id == Some(502)) {
+                    // e.g. "too many requests from this IP, more requests available in 84354 seconds"
+                    let pause = err.error_message.split_once("available in ")
+                        .and_then(|(_, rest)| rest.split(' ').next())
+                        .and_then(|seconds| seconds.parse::<u64>().ok())
+                        .map_or(DEFAULT_THROTTLE_PAUSE, Duration::from_secs);
+
+                    warn!(method, pause_s = pause.as_secs(), "throttled, pausing all calls");
+
+                    let mut next_slot = self.next_slot.lock().await;
+
+                    *next_slot = (*next_slot).max(Instant::now() + pause);
+                }
+            }
+        }
+    }
+}
+
+/// The Stack Exchange API. Every method takes the client to make the request with, since some feeds have to be read
+/// as a logged-in user.
+pub struct Api {
+    key: String,
+    // e.g. `https://api.stackexchange.com/2.3`, without a trailing slash
+    root: String,
+    // `include` list -> filter created for it
+    filters: Mutex<HashMap<String, String>>,
+    governor: Governor
+}
+
+impl Api {
+    pub fn new(key: &str, config: &ApiConfig, root: &str) -> Api {
+        Api {
+            key: key.to_owned(),
+            root: root.trim_end_matches('/').to_owned(),
+            filters: Mutex::new(HashMap::new()),
+            governor: Governor::new(config)
+        }
+    }
+
+    /// Makes a call through the governor, which may delay or refuse it.
+    async fn call<T: DeserializeOwned>(&self, method: &'static str, priority: Priority, request: reqwest::RequestBuilder) -> Result<Wrapper<T>> {
+        self.governor.admit(method, priority).await?;
+
+        let result = Self::send(request).await;
+
+        self.governor.record(method, &result).await;
+
+        result
+    }
+
+    /// Makes the request, or answers it from the capture being replayed. Live responses are recorded.
+    async fn send<T: DeserializeOwned>(request: reqwest::RequestBuilder) -> Result<Wrapper<T>> {
+        let (client, request) = request.build_split();
+        let request = request?;
+
+        let key = capture::request_key(request.url());
+
+        let (status, text) = match replay::response(&key) {
+            Some(response) => response?,
+            None => {
+                let response = client.execute(request).await?;
+                let status = response.status().as_u16();
+                let text = response.text().await?;
+
+                capture::record(Event::Api {
+                    request: key,
+                    status,
+                    body: text.clone()
+                });
+
+                (status, text)
+            }
+        };
+
+        let success = (200..300).contains(&status);
+
+        let wrapper: Wrapper<T> = match serde_json::from_str(&text) {
+            Ok(wrapper) => wrapper,
+            Err(err) if success => return Err(Box::new(err)),
+            Err(_) => return Err(Box::new(ApiError {
+                status,
+                error_id: None,
+                error_name: None,
+                error_message: text
+            }))
+        };
+
+        if wrapper.error_id.is_some() || !success {
+            return Err(Box::new(ApiError {
+                status,
+                error_id: wrapper.error_id,
+                error_name: wrapper.error_name,
+                error_message: wrapper.error_message.unwrap_or_default()
+            }));
+        }
+
+        Ok(wrapper)
+    }
+
+    /// Creates (once) a filter returning exactly `fields`, plus the wrapper fields.
+    async fn filter(&self, client: &reqwest::Client, fields: &[&str]) -> Result<String> {
+        let include = WRAPPER_FIELDS.iter().chain(fields).copied().collect::<Vec<&str>>().join(";");
+
+        if let Some(filter) = self.filters.lock().await.get(&include) {
+            return Ok(filter.clone());
+        }
+
+        let created: Wrapper<CreatedFilter> = self.call("/filters/create", Priority::Essential, client.get(format!("{}/filters/create", self.root)).query(&[
+            ("include", include.as_str()),
+            ("base", "none"),
+            ("unsafe", "false")
+        ])).await?;
+
+        let filter = created.items.into_iter().next().ok_or(MissingFilter {})?.filter;
+
+        self.filters.lock().await.insert(include, filter.clone());
+
+        Ok(filter)
+    }
+
+    /// Calls `path`, which is an instance of `method`, e.g. `/questions/1;2` for `/questions/{ids}`.
+    async fn get<T: ApiType>(&self, client: &reqwest::Client, method: &'static str, path: &str, priority: Priority, site: Option<&str>, params: &[(&str, String)]) -> Result<Wrapper<T>> {
+        let mut query = vec![("filter", self.filter(client, T::FIELDS).await?)];
+
+        if !self.key.is_empty() {
+            query.push(("key", self.key.clone()));
+        }
+
+        if let Some(site) = site {
+            query.push(("site", site.to_owned()));
+        }
+
+        query.extend(params.iter().map(|(name, value)| (*name, value.clone())));
+
+        self.call(method, priority, client.get(format!("{}{}", self.root, path)).query(&query)).await
+    }
+
+    pub async fn questions<T: ToString>(&self, client: &reqwest::Client, priority: Priority, site: &str, ids: &[T]) -> Result<Wrapper<Question>> {
+        self.get(client, "/questions/{ids}", &format!("/questions/{}", join_ids(ids)), priority, Some(site), &[]).await
+    }
+
+    /// Newest questions first, optionally only those with `tagged`.
+    pub async fn newest_questions(&self, client: &reqwest::Client, priority: Priority, site: &str, tagged: Option<&str>, page: u32, pagesize: u32) -> Result<Wrapper<Question>> {
+        let mut params = vec![
+            ("order", "desc".to_owned()),
+            ("sort", "creation".to_owned()),
+            ("page", page.to_string()),
+            ("pagesize", pagesize.to_string())
+        ];
+
+        if let Some(tagged) = tagged {
+            params.push(("tagged", tagged.to_owned()));
+        }
+
+        self.get(client, "/questions", "/questions", priority, Some(site), &params).await
+    }
+
+    pub async fn answers<T: ToString>(&self, client: &reqwest::Client, priority: Priority, site: &str, ids: &[T]) -> Result<Wrapper<Answer>> {
+        self.get(client, "/answers/{ids}", &format!("/answers/{}", join_ids(ids)), priority, Some(site), &[]).await
+    }
+
+    /// Newest answers to a question first.
+    pub async fn newest_answers(&self, client: &reqwest::Client, priority: Priority, site: &str, question_id: &str, page: u32, pagesize: u32) -> Result<Wrapper<Answer>> {
+        self.get(client, "/questions/{ids}/answers", &format!("/questions/{}/answers", question_id), priority, Some(site), &[
+            ("order", "desc".to_owned()),
+            ("sort", "creation".to_owned()),
+            ("page", page.to_string()),
+            ("pagesize", pagesize.to_string())
+        ]).await
+    }
+
+    pub async fn users<T: ToString>(&self, client: &reqwest::Client, priority: Priority, site: &str, ids: &[T]) -> Result<Wrapper<User>> {
+        self.get(client, "/users/{ids}", &format!("/users/{}", join_ids(ids)), priority, Some(site), &[]).await
+    }
+
+    pub async fn comments<T: ToString>(&self, client: &reqwest::Client, priority: Priority, site: &str, ids: &[T]) -> Result<Wrapper<Comment>> {
+        self.get(client, "/comments/{ids}", &format!("/comments/{}", join_ids(ids)), priority, Some(site), &[]).await
+    }
+
+    pub async fn sites(&self, client: &reqwest::Client, priority: Priority, page: u32, pagesize: u32) -> Result<Wrapper<Site>> {
+        self.get(client, "/sites", "/sites", priority, None, &[
+            ("page", page.to_string()),
+            ("pagesize", pagesize.to_string())
+        ]).await
+    }
+}
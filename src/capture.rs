@@ -0,0 +1,89 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use tracing::warn;
+
+use crate::time;
+
+/// Something that came in from outside, as written to a capture.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum Event {
+    /// A watch socket connection is about to be opened, after which the API is checked for anything missed
+    WatchConnecting,
+    /// A text frame from watch socket `replica`, including ones another replica also received
+    WatchFrame {
+        #[serde(default)]
+        replica: usize,
+        frame: String
+    },
+    /// A text frame from a room's chat socket, as seen by `user`
+    ChatFrame {
+        #[serde(rename = "roomId")]
+        room_id: u64,
+        user: String,
+        frame: String
+    },
+    /// A response from the Stack Exchange API. `request` is the URL without the key
+    Api { request: String, status: u16, body: String },
+}
+
+/// One line of a capture: an event and when it happened, in ms since the epoch.
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    // Not a u128 like everywhere else, since serde can't buffer those for `flatten`
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: Event
+}
+
+static RECORDER: Mutex<Option<LineWriter<File>>> = Mutex::new(None);
+
+/// Starts appending every event to the JSONL file at `path`.
+pub fn start_recording(path: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+
+    *RECORDER.lock().unwrap() = Some(LineWriter::new(file));
+
+    Ok(())
+}
+
+/// Writes `event` to the capture, if one is being recorded.
+pub fn record(event: Event) {
+    let mut recorder = RECORDER.lock().unwrap();
+
+    let writer = match recorder.as_mut() {
+        Some(writer) => writer,
+        None => return
+    };
+
+    let entry = Entry {
+        at: time() as u64,
+        event
+    };
+
+    let result = serde_json::to_string(&entry).map_err(std::io::Error::from).and_then(|line| writeln!(writer, "{}", line));
+
+    if let Err(err) = result {
+        warn!(%err, "failed to write to the capture, no longer recording");
+
+        *recorder = None;
+    }
+}
+
+/// Identifies an API request in a capture: its URL, minus the key, which shouldn't end up in captures.
+pub fn request_key(url: &url::Url) -> String {
+    let mut url = url.clone();
+
+    let query = url.query_pairs().filter(|(name, _)| name != "key").map(|(name, value)| (name.into_owned(), value.into_owned())).collect::<Vec<(String, String)>>();
+
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    url.to_string()
+}
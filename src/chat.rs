@@ -9,31 +9,75 @@ use url::Url;
 use std::collections::{HashSet, HashMap};
 
 use futures::StreamExt;
+use tokio::net::TcpStream;
+use tracing::{info, warn, debug, instrument, Instrument};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use tokio_tungstenite::tungstenite::{self, protocol::Message};
 
-use crate::{time, Site, Ids, login::User};
+use crate::{time, metrics, shutdown::Shutdown, capture, Ids, Config, login::User, rotate::Rotation};
 
 #[derive(Deserialize)]
 struct WsAuth {
     url: String
 }
 
+/// A room's websocket, as returned by [`connect`].
+pub type ChatStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A page of a room's history, from [`fetch_events`].
 #[derive(Deserialize)]
-struct Events {
-    time: u64,
-    events: Vec<Event>
+pub struct Events {
+    /// The latest event time in the room, to resume from
+    pub time: u64,
+    pub events: Vec<Event>
 }
 
 #[derive(Debug, Deserialize)]
-struct Event {
-    event_type: u8,
-    message_id: Option<u64>,
-    content: Option<String>
+pub struct Event {
+    pub id: Option<u64>,
+    /// 1 for a new message, 8 for a mention and 18 for a reply
+    pub event_type: u8,
+    pub message_id: Option<u64>,
+    /// The message as rendered HTML
+    pub content: Option<String>
 }
 
+/// One room's part of a websocket frame.
 #[derive(Debug, Deserialize)]
-struct RoomData {
-    e: Option<Vec<Event>>
+pub struct RoomData {
+    pub e: Option<Vec<Event>>,
+    /// The event time the frame brings the room up to
+    pub t: Option<u64>
+}
+
+#[derive(Debug)]
+pub struct PostFailed {
+    status: u16,
+    text: String
+}
+
+impl Error for PostFailed {}
+
+impl fmt::Display for PostFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "messages/new returned {}: {}", self.status, self.text)
+    }
+}
+
+pub enum PostOutcome {
+    Sent,
+    /// Chat asked for this many seconds before trying again
+    Cooldown(u64),
+    Failed(Box<dyn Error + Send + Sync>)
+}
+
+// Kept across reconnects to a room
+#[derive(Default)]
+struct RoomState {
+    ack: Mutex<HashSet<u64>>,
+    // Last event time processed, sent as `l` when reconnecting
+    cursor: Mutex<Option<u64>>,
+    acked_back: Mutex<bool>
 }
 
 #[derive(Debug)]
@@ -47,7 +91,7 @@ impl fmt::Display for MissingAckBack {
     }
 }
 
-async fn ack_back(room_id: u64, user: Arc<User>, ack: Arc<Mutex<HashSet<u64>>>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn ack_back(room_id: u64, user: Arc<User>, state: Arc<RoomState>, dry_run: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     async fn find_script(dom: &Dom) -> Option<String> {
         fn search_node(node: &Node) -> Option<String> {
             match node {
@@ -59,7 +103,7 @@ async fn ack_back(room_id: u64, user: Arc<User>, ack: Arc<Mutex<HashSet<u64>>>)
                         }
                     } else {
                         for child in &element.children {
-                            let result = search_node(&child);
+                            let result = search_node(child);
 
                             if result.is_some() {
                                 return result;
@@ -74,7 +118,7 @@ async fn ack_back(room_id: u64, user: Arc<User>, ack: Arc<Mutex<HashSet<u64>>>)
         }
         
         for child in &dom.children {
-            let result = search_node(&child);
+            let result = search_node(child);
 
             if let Some(script) = result {
                 return Some(script);
@@ -84,7 +128,7 @@ async fn ack_back(room_id: u64, user: Arc<User>, ack: Arc<Mutex<HashSet<u64>>>)
         None
     }
     
-    let html = user.client.get(format!("https://chat.stackexchange.com/rooms/{}", room_id)).send().await?.error_for_status()?.text().await?;
+    let html = user.client.get(user.chat_url(&format!("/rooms/{}", room_id))).send().await?.error_for_status()?.text().await?;
     let dom = Dom::parse(&html)?;
     let script = find_script(&dom).await.ok_or(MissingAckBack {})?;
     
@@ -92,17 +136,24 @@ async fn ack_back(room_id: u64, user: Arc<User>, ack: Arc<Mutex<HashSet<u64>>>)
     let args = &suffix[..suffix.find(");").ok_or(MissingAckBack {})?];
     let ids_dict = args.rsplit_once('\n').ok_or(MissingAckBack {})?.1.trim_start();
     
-    if ids_dict != "{}" {
-        let ids = ids_dict[1..ids_dict.len() - 1].split(',').map(|p| p.split_once(':').unwrap().0).collect::<Vec<&str>>();
+    let ids_dict = ids_dict.strip_prefix('{').and_then(|ids_dict| ids_dict.strip_suffix('}')).ok_or(MissingAckBack {})?;
+    
+    if !ids_dict.is_empty() {
+        let ids = ids_dict.split(',').map(|p| p.split_once(':').map(|(id, _)| id).ok_or(MissingAckBack {})).collect::<Result<Vec<&str>, MissingAckBack>>()?;
         
         for id in ids {
-            if ack.lock().await.insert(id.parse::<u64>()?) {
-                user.client.post("https://chat.stackexchange.com/messages/ack").form(&[
-                    ("id", &id.to_string()),
-                    ("fkey", &user.fkey)
-                ]).send().await?.error_for_status()?;
+            let id = id.parse::<u64>()?;
+            
+            if state.ack.lock().await.insert(id) {
+                if dry_run {
+                    debug!(room = room_id, message_id = %id, "dry run, not acking pending mention");
 
-                println!("ack_back {}", id);
+                    continue;
+                }
+
+                ack(id, &user).await?;
+
+                debug!(room = room_id, message_id = %id, "acked pending mention");
             }
         }
     }
@@ -110,29 +161,28 @@ async fn ack_back(room_id: u64, user: Arc<User>, ack: Arc<Mutex<HashSet<u64>>>)
     Ok(())
 }
 
-fn urls_from_dom(dom: &Dom) -> Vec<Url> {
-    fn search_node(node: &Node, urls: &mut Vec<Url>) {
-        match node {
-            Node::Element(element) => {
-                if element.name == "a" && element.attributes.contains_key("href") {
-                    // Assume we are in sandbox; since all URLs we're interested are on a separate domain, this doesn't matter
-                    if let Ok(url) = Url::parse("https://chat.stackexchange.com/rooms/1/sandbox").unwrap().join(element.attributes.get("href").unwrap().as_ref().unwrap()) {
+/// Every link in `dom`, with relative ones resolved against `base`, the page they were posted on.
+fn urls_from_dom(dom: &Dom, base: &Url) -> Vec<Url> {
+    fn search_node(node: &Node, base: &Url, urls: &mut Vec<Url>) {
+        if let Node::Element(element) = node {
+            if element.name == "a" {
+                if let Some(Some(href)) = element.attributes.get("href") {
+                    if let Ok(url) = base.join(href) {
                         urls.push(url);
                     }
-                } else {
-                    for child in &element.children {
-                        search_node(&child, urls);
-                    }
+                }
+            } else {
+                for child in &element.children {
+                    search_node(child, base, urls);
                 }
             }
-            _ => ()
         }
     }
     
     let mut urls: Vec<Url> = Vec::new();
     
     for child in &dom.children {
-        search_node(&child, &mut urls);
+        search_node(child, base, &mut urls);
     }
     
     urls
@@ -142,10 +192,10 @@ fn url_ids(urls: &Vec<Url>, site: &str) -> HashSet<String> {
     let mut ids: HashSet<String> = HashSet::new();
     
     for url in urls {
-        if url.domain().map_or(false, |domain| domain == site) {
-            let path = url.path_segments().unwrap().collect::<Vec<&str>>();
+        if url.domain() == Some(site) {
+            let path = url.path_segments().map_or_else(Vec::new, |segments| segments.collect::<Vec<&str>>());
             
-            if path.len() != 0 {
+            if !path.is_empty() {
                 match (path[0], path.len()) {
                     ("questions", 2) | ("questions", 3) | ("q", 2) | ("q", 3) => {
                         ids.insert(path[1].to_owned());
@@ -163,67 +213,196 @@ fn url_ids(urls: &Vec<Url>, site: &str) -> HashSet<String> {
     ids
 }
 
-async fn known_ids(site: &Site, events: &Vec<Event>, ids: Arc<Mutex<Ids>>) {
+async fn known_ids(room_id: u64, user: &User, config: &Config, events: &Vec<Event>, ids: Arc<Mutex<Ids>>) {
+    let base = match Url::parse(&user.chat_url(&format!("/rooms/{}", room_id))) {
+        Ok(base) => base,
+        Err(err) => {
+            warn!(room = room_id, %err, "bad chat URL, not reading known IDs");
+            
+            return;
+        }
+    };
+    
     for event in events {
-        if event.event_type == 1 && event.content.is_some() {
-            let dom = Dom::parse(&event.content.as_ref().unwrap()).unwrap();
+        if let (1, Some(content)) = (event.event_type, &event.content) {
+            let dom = match Dom::parse(content) {
+                Ok(dom) => dom,
+                Err(_) => continue
+            };
             
-            let urls = urls_from_dom(&dom);
+            let urls = urls_from_dom(&dom, &base);
             
-            match site {
-                Site::CodeGolf => {
-                    for id in url_ids(&urls, "codegolf.stackexchange.com") {
-                        ids.lock().await.p_200.insert(id);
-                    }
+            for site in config.get_sites().values() {
+                for id in url_ids(&urls, site.host()) {
+                    ids.lock().await.insert(room_id, &site.id, &id);
+                }
+            }
+        }
+    }
+}
 
-                    for id in url_ids(&urls, "codegolf.meta.stackexchange.com") {
-                        ids.lock().await.p_202.insert(id);
-                    }
+/// One user's presence in a room, shared by all of that user's replica connections to it.
+pub(crate) struct ChatRoom {
+    room_id: u64,
+    user: Arc<User>,
+    ids: Arc<Mutex<Ids>>,
+    config: Arc<Config>,
+    // Shared by every user's connections to the room, in which this user's replicas start at `first_slot`
+    rotation: Arc<Rotation>,
+    first_slot: usize,
+    state: Arc<RoomState>,
+    shutdown: Shutdown
+}
+
+impl ChatRoom {
+    pub fn new(room_id: u64, user: Arc<User>, ids: Arc<Mutex<Ids>>, config: Arc<Config>, rotation: Arc<Rotation>, first_slot: usize, shutdown: Shutdown) -> Arc<ChatRoom> {
+        Arc::new(ChatRoom {
+            room_id,
+            user,
+            ids,
+            config,
+            rotation,
+            first_slot,
+            state: Arc::new(RoomState::default()),
+            shutdown
+        })
+    }
+
+    /// Drops events another replica has already handled for this user.
+    async fn fresh_events(&self, events: Vec<Event>) -> Vec<Event> {
+        let mut fresh = Vec::new();
+
+        for event in events {
+            if event.id.is_none() || self.rotation.first_seen(format!("{}:{}", self.user.id, event.id.unwrap())).await {
+                fresh.push(event);
+            }
+        }
+
+        fresh
+    }
+
+    /// Handles a text frame from the room's websocket.
+    pub async fn handle_frame(&self, string: &str) {
+        let data = match decode_frame(string) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!(%err, "skipping bad frame");
+                
+                return;
+            }
+        };
+        
+        let own_key = format!("r{}", self.room_id);
+        
+        // The socket carries every room the user is in, but only this room's events say anything about its links or
+        // its cursor. Mentions are acked wherever they come from.
+        for (key, room_data) in data {
+            let own = key == own_key;
+            
+            if let Some(events) = room_data.e {
+                if let Err(err) = self.handle_events(events, own).await {
+                    warn!(%err, "failed to handle events");
                 }
-                // PLDI
-                Site::PLDI => {
-                    for id in url_ids(&urls, "languagedesign.stackexchange.com") {
-                        ids.lock().await.p_716.insert(id);
-                    }
+            }
+            
+            if let (true, Some(t)) = (own, room_data.t) {
+                let mut cursor = self.state.cursor.lock().await;
+                
+                *cursor = Some(cursor.map_or(t, |cursor| cursor.max(t)));
+            }
+        }
+    }
+    
+    async fn handle_events(&self, events: Vec<Event>, own: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let events = self.fresh_events(events).await;
 
-                    for id in url_ids(&urls, "languagedesign.meta.stackexchange.com") {
-                        ids.lock().await.p_717.insert(id);
+        if own {
+            known_ids(self.room_id, &self.user, &self.config, &events, Arc::clone(&self.ids)).await;
+        }
+        
+        for event in &events {
+            if let (8 | 18, Some(message_id)) = (event.event_type, event.message_id) {
+                if self.state.ack.lock().await.insert(message_id) {
+                    // Acking is posting too, as far as a dry run is concerned
+                    if self.config.get_dry_run() {
+                        debug!(message_id, "dry run, not acking");
+                        
+                        continue;
                     }
+                    
+                    ack(message_id, &self.user).await?;
+                    
+                    debug!(message_id, "acked");
                 }
             }
         }
+        
+        Ok(())
     }
 }
 
-pub async fn find_known_ids(room_id: u64, site: &Site, user: Arc<User>, ids: Arc<Mutex<Ids>>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let events: Events = serde_json::from_str(&(user.client.post(format!("https://chat.stackexchange.com/chats/{}/events", room_id)).form(&[
-        ("since", "0"),
-        ("mode", "Messages"),
-        ("msgCount", "100"),
+/// Posts `text` to a room as `user`.
+pub async fn post(room_id: u64, text: &str, user: &User) -> PostOutcome {
+    let response = match user.client.post(user.chat_url(&format!("/chats/{}/messages/new", room_id))).form(&[
+        ("text", text),
         ("fkey", &user.fkey)
-    ]).send().await?.error_for_status()?.text().await?))?;
+    ]).send().await {
+        Ok(response) => response,
+        Err(err) => return PostOutcome::Failed(Box::new(err))
+    };
     
-    known_ids(site, &events.events, Arc::clone(&ids)).await;
+    let status = response.status().as_u16();
     
-    Ok(())
+    if response.status().is_success() {
+        return PostOutcome::Sent;
+    }
+    
+    let text = response.text().await.unwrap_or_default();
+    
+    if status == 409 {
+        let cooldown = text.strip_prefix("You can perform this action again in ").and_then(|rest| rest.split_once(' ')).and_then(|(seconds, _)| seconds.parse::<u64>().ok());
+        
+        if let Some(cooldown) = cooldown {
+            return PostOutcome::Cooldown(cooldown);
+        }
+    }
+    
+    PostOutcome::Failed(Box::new(PostFailed {
+        status,
+        text
+    }))
 }
 
-async fn connect_chat_ws(room_id: u64, site: &Site, log_id: &str, user: Arc<User>, ids: Arc<Mutex<Ids>>, ack: Arc<Mutex<HashSet<u64>>>, kill_offset: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let ws_auth: WsAuth = serde_json::from_str(&(user.client.post("https://chat.stackexchange.com/ws-auth").form(&[
-        ("roomid", &room_id.to_string()),
+/// Marks a mention or reply to `user` as read, so it stops showing up in their inbox.
+pub async fn ack(message_id: u64, user: &User) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    user.client.post(user.chat_url("/messages/ack")).form(&[
+        ("id", &message_id.to_string()),
         ("fkey", &user.fkey)
-    ]).send().await?.error_for_status()?.text().await?))?;
+    ]).send().await?.error_for_status()?;
+    
+    Ok(())
+}
+
+/// The room's last 100 messages if `since` is 0, otherwise every event after the event time `since`.
+pub async fn fetch_events(room_id: u64, since: u64, user: &User) -> Result<Events, Box<dyn std::error::Error + Send + Sync>> {
+    let since_param = since.to_string();
     
-    let events: Events = serde_json::from_str(&(user.client.post(format!("https://chat.stackexchange.com/chats/{}/events", room_id)).form(&[
-        ("since", "0"),
-        ("mode", "Messages"),
+    Ok(serde_json::from_str(&(user.client.post(user.chat_url(&format!("/chats/{}/events", room_id))).form(&[
+        ("since", since_param.as_str()),
+        ("mode", if since == 0 { "Messages" } else { "Events" }),
         ("msgCount", "100"),
         ("fkey", &user.fkey)
+    ]).send().await?.error_for_status()?.text().await?))?)
+}
+
+/// Opens a room's websocket, which replays everything after the event time `since` before anything new.
+pub async fn connect(room_id: u64, since: u64, user: &User) -> Result<ChatStream, Box<dyn std::error::Error + Send + Sync>> {
+    let ws_auth: WsAuth = serde_json::from_str(&(user.client.post(user.chat_url("/ws-auth")).form(&[
+        ("roomid", &room_id.to_string()),
+        ("fkey", &user.fkey)
     ]).send().await?.error_for_status()?.text().await?))?;
     
-    known_ids(site, &events.events, Arc::clone(&ids)).await;
-    
-    let ws_auth_uri = format!("{}?l={}", ws_auth.url, events.time).parse::<Uri>()?;
+    let ws_auth_uri = format!("{}?l={}", ws_auth.url, since).parse::<Uri>()?;
     
     let request = tungstenite::handshake::client::Request::builder()
         .method("GET")
@@ -232,23 +411,57 @@ async fn connect_chat_ws(room_id: u64, site: &Site, log_id: &str, user: Arc<User
         .header("Upgrade", "websocket")
         .header("Sec-WebSocket-Version", "13")
         .header("Sec-WebSocket-Key", tungstenite::handshake::client::generate_key())
-        .header("Origin", "https://chat.stackexchange.com")
+        .header("Origin", user.chat_url(""))
         .uri(ws_auth_uri)
         .body(())?;
     
-    let mut ws_stream = tokio_tungstenite::connect_async(request).await?.0;
+    Ok(tokio_tungstenite::connect_async(request).await?.0)
+}
+
+/// Splits a text frame from a room's websocket by room, keyed `r{room ID}`.
+pub fn decode_frame(frame: &str) -> serde_json::Result<HashMap<String, RoomData>> {
+    serde_json::from_str(frame)
+}
+
+pub(crate) async fn find_known_ids(room_id: u64, config: &Config, user: Arc<User>, ids: Arc<Mutex<Ids>>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let events = fetch_events(room_id, 0, &user).await?;
     
-    println!("{}-{}: open", log_id, room_id);
+    known_ids(room_id, &user, config, &events.events, Arc::clone(&ids)).await;
     
-    let duration = tokio::time::sleep(if kill_offset {
-        Duration::from_millis(3600000)
-    } else {
-        Duration::from_millis(7200000)
-    });
+    Ok(())
+}
+
+/// Connects to the room's websocket, resuming from `cursor` (the last event time processed) if there is one.
+///
+/// Anything that happened since `cursor` is fetched and handled before connecting, so mentions and links posted while
+/// disconnected aren't lost; otherwise the last 100 messages are read to seed the known IDs.
+async fn connect_chat_ws(room: &Arc<ChatRoom>, lifetime: Duration) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (room_id, user, state) = (room.room_id, &room.user, &room.state);
+    
+    let last_seen = *state.cursor.lock().await;
+    
+    let events = fetch_events(room_id, last_seen.unwrap_or(0), user).await?;
+    
+    match last_seen {
+        Some(since) => {
+            info!(events = events.events.len(), since, "replaying missed events");
+            
+            room.handle_events(events.events, true).await?;
+        }
+        None => known_ids(room_id, user, &room.config, &events.events, Arc::clone(&room.ids)).await
+    }
+    
+    *state.cursor.lock().await = Some(events.time);
+    
+    let mut ws_stream = connect(room_id, events.time, user).await?;
+    
+    info!("open");
+    
+    let duration = tokio::time::sleep(lifetime);
     
     let ping: Arc<Mutex<u128>> = Arc::new(Mutex::new(time()));
     
-    let pong = {
+    let mut pong = {
         let ping = ping.clone();
         
         tokio::spawn(async move {
@@ -257,7 +470,7 @@ async fn connect_chat_ws(room_id: u64, site: &Site, log_id: &str, user: Arc<User
             loop {
                 interval.tick().await;
                 
-                if time() - *ping.lock().await > 45000 {
+                if time().saturating_sub(*ping.lock().await) > 45000 {
                     break;
                 }
             }
@@ -265,79 +478,96 @@ async fn connect_chat_ws(room_id: u64, site: &Site, log_id: &str, user: Arc<User
     };
     
     let mut chat = {
-        let user = user.clone();
+        let room = Arc::clone(room);
         let ping = ping.clone();
-        let log_id = log_id.to_owned();
         
         tokio::spawn(async move {
-            while let Some(msg_r) = ws_stream.next().await {
-                let msg = msg_r.unwrap();
-
-                match msg {
-                    Message::Text(string) => {
-                        let data: HashMap<String, RoomData> = serde_json::from_str(&string).unwrap();
-
-                        *ping.lock().await = time();
-
-                        for room in data {
-                            if let Some(events) = room.1.e {
-                                for event in events {
-                                    match event.event_type {
-                                        8 | 18 => {
-                                            if ack.lock().await.insert(event.message_id.unwrap()) {
-                                                user.client.post("https://chat.stackexchange.com/messages/ack").form(&[
-                                                    ("id", &event.message_id.unwrap().to_string()),
-                                                    ("fkey", &user.fkey)
-                                                ]).send().await.unwrap().error_for_status().unwrap();
-                                                
-                                                println!("{}-{}: ack {}", log_id, room_id, event.message_id.unwrap());
-                                            }
-                                        }
-                                        _ => ()
-                                    }
-                                }
-                            }
-                        }
+            loop {
+                let msg_r = match room.shutdown.until(ws_stream.next()).await {
+                    Some(Some(msg_r)) => msg_r,
+                    Some(None) => break,
+                    None => {
+                        ws_stream.close(None).await?;
+                        
+                        break;
                     }
-                    _ => ()
+                };
+                
+                if let Message::Text(string) = msg_r? {
+                    *ping.lock().await = time();
+                    
+                    capture::record(capture::Event::ChatFrame {
+                        room_id: room.room_id,
+                        user: room.user.id.clone(),
+                        frame: string.clone()
+                    });
+                    
+                    room.handle_frame(&string).await;
                 }
             }
-        })
+            
+            Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+        }.in_current_span())
     };
     
-    tokio::select!(
+    let result = tokio::select!(
         _ = duration => {
-            println!("{}-{}: close (alive over {} hours)", log_id, room_id, if kill_offset { 1 } else { 2 });
+            info!(lifetime_mins = lifetime.as_secs() / 60, "close, lifetime over");
             
-            chat.abort();
+            metrics::inc("npsp_reconnects_total", &[("socket", "chat"), ("reason", "lifetime")]);
+            
+            Ok(())
         }
-        _ = pong => {
-            println!("{}-{}: close (ping too high - {}s - or disconn)", log_id, room_id, (time() - *ping.lock().await) / 1000);
+        _ = &mut pong => {
+            let silent = time().saturating_sub(*ping.lock().await);
+            
+            warn!(silent_s = silent / 1000, "close, no frames for too long");
+            
+            metrics::inc("npsp_reconnects_total", &[("socket", "chat"), ("reason", "ping")]);
             
-            chat.abort();
+            Ok(())
         }
         chat_r = &mut chat => {
-            println!("{}-{}: close (stream closed)", log_id, room_id);
+            let reason = match chat_r {
+                Ok(Ok(())) if room.shutdown.is_requested() => "shutdown",
+                Ok(Ok(())) => "closed",
+                _ => "error"
+            };
             
-            chat_r.unwrap();
+            info!(reason, "close, stream ended");
+            
+            metrics::inc("npsp_reconnects_total", &[("socket", "chat"), ("reason", reason)]);
+            
+            chat_r?
         }
     );
     
-    Ok(())
+    chat.abort();
+    pong.abort();
+    
+    result
 }
 
-pub async fn chat_ws(room_id: u64, site: &Site, log_id: &str, user: Arc<User>, ids: Arc<Mutex<Ids>>) {
-    let ack: Arc<Mutex<HashSet<u64>>> = Arc::new(Mutex::new(HashSet::new()));
-    
-    let mut first = true;
-    
-    loop {
-        ack_back(room_id, Arc::clone(&user), Arc::clone(&ack)).await.unwrap();
+/// Keeps one of a room's replica websockets connected until a fatal error, which is returned; transient failures are
+/// retried with backoff.
+#[instrument(name = "chat", skip_all, fields(user = %room.user.id, room = room.room_id, replica))]
+pub(crate) async fn chat_ws(room: Arc<ChatRoom>, replica: usize) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    room.rotation.run(room.first_slot + replica, |lifetime| {
+        let room = Arc::clone(&room);
         
-        connect_chat_ws(room_id, site, log_id, Arc::clone(&user), Arc::clone(&ids), Arc::clone(&ack), log_id == "sandbox" && first).await.unwrap();
-        
-        first = false;
-
-        tokio::time::sleep(Duration::from_millis(2000)).await;
-    }
+        async move {
+            // Pending mentions from before we started; after that, the replay on reconnect picks them up
+            let mut acked_back = room.state.acked_back.lock().await;
+            
+            if !*acked_back {
+                ack_back(room.room_id, Arc::clone(&room.user), Arc::clone(&room.state), room.config.get_dry_run()).await?;
+                
+                *acked_back = true;
+            }
+            
+            drop(acked_back);
+            
+            connect_chat_ws(&room, lifetime).await
+        }
+    }).await
 }
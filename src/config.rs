@@ -1,12 +1,30 @@
-use std::{collections::HashMap, fmt::{self, Display, Debug}};
-use serde::Deserialize;
+use std::{collections::{HashMap, BTreeSet}, fmt::{self, Display, Debug}};
+use std::time::Duration;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
 use std::error::Error;
+use url::Url;
 
 pub struct Config {
     inner: UnlinkedConfig,
 }
 
 impl Config {
+    /// The config as loaded, with defaults filled in. Secrets are left out when it's serialised.
+    pub fn get_effective(&self) -> &UnlinkedConfig {
+        &self.inner
+    }
+
+    /// Whether every route is in dry-run mode, from `dryRun` or `--dry-run`.
+    pub fn get_dry_run(&self) -> bool {
+        self.inner.dry_run
+    }
+
+    pub fn get_state_dir(&self) -> &Path {
+        &self.inner.state_dir
+    }
+
     pub fn get_api_key(&self) -> &str {
         &self.inner.api_key
     }
@@ -27,36 +45,284 @@ impl Config {
         &self.inner.rooms
     }
 
+    pub fn get_rotation(&self) -> &RotationsConfig {
+        &self.inner.rotation
+    }
+
+    pub fn get_polling(&self) -> &PollingConfig {
+        &self.inner.polling
+    }
+
+    pub fn get_api(&self) -> &ApiConfig {
+        &self.inner.api
+    }
+
+    pub fn get_wait_for_api(&self) -> &WaitForApiConfig {
+        &self.inner.wait_for_api
+    }
+
+    pub fn get_logging(&self) -> &LoggingConfig {
+        &self.inner.logging
+    }
+
+    pub fn get_metrics(&self) -> Option<&MetricsConfig> {
+        self.inner.metrics.as_ref()
+    }
+
+    pub fn get_admin(&self) -> Option<&AdminConfig> {
+        self.inner.admin.as_ref()
+    }
+
+    pub fn get_shutdown(&self) -> &ShutdownConfig {
+        &self.inner.shutdown
+    }
+
+    pub fn get_endpoints(&self) -> &EndpointsConfig {
+        &self.inner.endpoints
+    }
+
+    /// The endpoints as `user_id` sees them: logging in through its `loginSite`, and chatting on the server its rooms
+    /// are on. Either falls back to `endpoints`.
+    pub fn get_user_endpoints(&self, user_id: &str) -> EndpointsConfig {
+        let endpoints = &self.inner.endpoints;
+
+        EndpointsConfig {
+            site_url: self.inner.users.get(user_id).map_or_else(|| endpoints.site_url.clone(), |user| user.login_url(endpoints)),
+            // Linking made sure there's at most one
+            chat_server: self.inner.user_chat_servers(user_id).into_iter().next().unwrap_or_else(|| endpoints.chat_server.clone()),
+            ..endpoints.clone()
+        }
+    }
+
+    pub fn get_backfill(&self) -> &BackfillConfig {
+        &self.inner.backfill
+    }
+
+    pub fn get_reconcile(&self) -> Option<&ReconcileConfig> {
+        self.inner.reconcile.as_ref()
+    }
+
     pub fn get_route_configs(&self) -> HashMap<&str, RouteConfig<'_>> {
-        self.inner.routes.iter().map(|(id, route)| (id.as_str(), RouteConfig {
+        self.inner.routes.iter().map(|(id, route)| (id.as_str(), self.link_route(id, route))).collect()
+    }
+
+    pub fn get_route_config(&self, id: &str) -> Option<RouteConfig<'_>> {
+        self.inner.routes.get_key_value(id).map(|(id, route)| self.link_route(id, route))
+    }
+
+    fn link_route<'a>(&'a self, id: &'a str, route: &'a UnlinkedRouteConfig) -> RouteConfig<'a> {
+        let watch_socket = self.inner.watch_sockets.get(&route.watch_socket).unwrap();
+
+        RouteConfig {
+            id,
+            user_id: &route.user,
             user: self.inner.users.get(&route.user).unwrap(),
-            watch_socket: self.inner.watch_sockets.get(&route.watch_socket).unwrap(),
+            watch_socket_id: &route.watch_socket,
+            watch_socket,
+            site: self.inner.sites.get(&watch_socket.site).unwrap(),
             room: self.inner.rooms.get(&route.room).unwrap(),
 
             force_user_client_for_watch_socket: route.force_user_client_for_watch_socket,
-        })).collect()
+            coalesce_window: route.coalesce_window_ms.map(Duration::from_millis),
+            dry_run: route.dry_run || self.inner.dry_run,
+        }
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UnlinkedConfig {
+    #[serde(skip_serializing)]
     api_key: String,
     sites: HashMap<String, SiteConfig>,
     users: HashMap<String, UserConfig>,
     watch_sockets: HashMap<String, WatchSocketConfig>,
     rooms: HashMap<String, RoomConfig>,
     routes: HashMap<String, UnlinkedRouteConfig>,
+    #[serde(default)]
+    rotation: RotationsConfig,
+    #[serde(default)]
+    polling: PollingConfig,
+    #[serde(default)]
+    reconcile: Option<ReconcileConfig>,
+    #[serde(default)]
+    backfill: BackfillConfig,
+    #[serde(default)]
+    api: ApiConfig,
+    #[serde(default)]
+    wait_for_api: WaitForApiConfig,
+    #[serde(default)]
+    logging: LoggingConfig,
+    #[serde(default)]
+    metrics: Option<MetricsConfig>,
+    #[serde(default)]
+    admin: Option<AdminConfig>,
+    #[serde(default)]
+    shutdown: ShutdownConfig,
+    #[serde(default)]
+    endpoints: EndpointsConfig,
+    /// Log chat messages instead of posting them, for every route
+    #[serde(default)]
+    dry_run: bool,
+    /// Where the queue, known IDs, sessions and other state kept between runs are saved
+    #[serde(default = "UnlinkedConfig::default_state_dir")]
+    state_dir: PathBuf,
 }
 
 impl UnlinkedConfig {
+    /// Reads a config file such as `config.json`, which still has to be linked before it's used.
+    pub fn load(path: impl AsRef<Path>) -> Result<UnlinkedConfig, Box<dyn Error + Send + Sync>> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    fn default_state_dir() -> PathBuf {
+        PathBuf::from("tmp")
+    }
+
+    /// Turns on dry-run mode for every route, whatever the file says.
+    pub fn force_dry_run(&mut self) {
+        self.dry_run = true;
+    }
+
+    /// Keeps state somewhere other than the file says, such as a scratch directory for a replay.
+    pub fn set_state_dir(&mut self, state_dir: impl Into<PathBuf>) {
+        self.state_dir = state_dir.into();
+    }
+
+    // Every chat server `user_id` has a route to a room on
+    fn user_chat_servers(&self, user_id: &str) -> BTreeSet<String> {
+        self.routes.values()
+            .filter(|route| route.user == user_id)
+            .filter_map(|route| self.rooms.get(&route.room))
+            .map(|room| room.server_url(&self.endpoints))
+            .collect()
+    }
+
     pub fn link(self) -> Result<Config, ConfigLinkingError> {
+        for (id, watch_socket) in &self.watch_sockets {
+            if !self.sites.contains_key(&watch_socket.site) {
+                return Err(ConfigLinkingError {
+                    message: format!("missing site `{}` in watch socket `{}`", watch_socket.site, id)
+                });
+            }
+        }
+
+        for (id, room) in &self.rooms {
+            if room.id.parse::<u64>().is_err() {
+                return Err(ConfigLinkingError {
+                    message: format!("invalid id `{}` in room `{}`", room.id, id)
+                });
+            }
+        }
+
+        for (id, rotation) in [("watch", &self.rotation.watch), ("chat", &self.rotation.chat)] {
+            if rotation.replicas == 0 || rotation.lifetime_ms == 0 {
+                return Err(ConfigLinkingError {
+                    message: format!("`{}` rotation needs at least one replica and a nonzero lifetime", id)
+                });
+            }
+        }
+
+        if self.polling.min_interval_ms == 0 || self.polling.min_interval_ms > self.polling.max_interval_ms {
+            return Err(ConfigLinkingError {
+                message: "polling needs 0 < `minIntervalMs` <= `maxIntervalMs`".to_owned()
+            });
+        }
+
+        if self.api.max_requests_per_second <= 0.0 {
+            return Err(ConfigLinkingError {
+                message: "`api.maxRequestsPerSecond` must be positive".to_owned()
+            });
+        }
+
+        if self.wait_for_api.attempts == 0 {
+            return Err(ConfigLinkingError {
+                message: "`waitForApi.attempts` must be at least 1".to_owned()
+            });
+        }
+
+        if self.logging.file.as_ref().is_some_and(|file| file.max_files == Some(0)) {
+            return Err(ConfigLinkingError {
+                message: "`logging.file.maxFiles` must be at least 1".to_owned()
+            });
+        }
+
+        if let Some(admin) = &self.admin {
+            if !admin.listen.starts_with("unix:") && !admin.listen.parse::<SocketAddr>().is_ok_and(|address| address.ip().is_loopback()) {
+                return Err(ConfigLinkingError {
+                    message: format!("`admin.listen` must be a loopback address or `unix:/path`, not `{}`", admin.listen)
+                });
+            }
+
+            if admin.token.is_empty() {
+                return Err(ConfigLinkingError {
+                    message: "`admin.token` must be set".to_owned()
+                });
+            }
+        }
+
+        for (id, url, schemes) in [
+            ("siteUrl", &self.endpoints.site_url, ["http", "https"]),
+            ("chatServer", &self.endpoints.chat_server, ["http", "https"]),
+            ("apiRoot", &self.endpoints.api_root, ["http", "https"]),
+            ("socketUrl", &self.endpoints.socket_url, ["ws", "wss"])
+        ] {
+            if !Url::parse(url).is_ok_and(|url| schemes.contains(&url.scheme())) {
+                return Err(ConfigLinkingError {
+                    message: format!("`endpoints.{}` must be a {} or {} URL, not `{}`", id, schemes[0], schemes[1], url)
+                });
+            }
+        }
+
+        let is_http = |url: &str| Url::parse(url).is_ok_and(|url| url.scheme() == "http" || url.scheme() == "https");
+
+        for (id, user) in &self.users {
+            if !is_http(&user.login_url(&self.endpoints)) {
+                return Err(ConfigLinkingError {
+                    message: format!("invalid `loginSite` in user `{}`", id)
+                });
+            }
+
+            // A user only has one chat session, so its rooms have to share a server
+            if self.user_chat_servers(id).len() > 1 {
+                return Err(ConfigLinkingError {
+                    message: format!("user `{}` has routes to rooms on more than one chat server", id)
+                });
+            }
+        }
+
+        for (id, room) in &self.rooms {
+            if !is_http(&room.server_url(&self.endpoints)) {
+                return Err(ConfigLinkingError {
+                    message: format!("invalid `server` in room `{}`", id)
+                });
+            }
+        }
+
+        if self.reconcile.as_ref().is_some_and(|reconcile| reconcile.interval_ms == 0) {
+            return Err(ConfigLinkingError {
+                message: "reconciliation needs a nonzero `intervalMs`".to_owned()
+            });
+        }
+
         for (id, route) in &self.routes {
             if !self.users.contains_key(&route.user) {
                 return Err(ConfigLinkingError {
                     message: format!("missing user `{}` in route `{}`", route.user, id)
                 });
             }
+
+            if !self.watch_sockets.contains_key(&route.watch_socket) {
+                return Err(ConfigLinkingError {
+                    message: format!("missing watch socket `{}` in route `{}`", route.watch_socket, id)
+                });
+            }
+
+            if !self.rooms.contains_key(&route.room) {
+                return Err(ConfigLinkingError {
+                    message: format!("missing room `{}` in route `{}`", route.room, id)
+                });
+            }
         }
 
         Ok(Config {
@@ -87,54 +353,600 @@ impl Debug for ConfigLinkingError {
 
 impl Error for ConfigLinkingError {}
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SiteConfig {
+    /// API site parameter, e.g. `codegolf.meta`
     pub id: String,
     pub name: String,
+    /// e.g. `https://codegolf.meta.stackexchange.com`
     pub url: String,
+    /// Numeric site ID used in qa.sockets actions, e.g. `202`
     pub websocket_id: String,
 }
 
-#[derive(Deserialize)]
+impl SiteConfig {
+    /// `url` without the scheme or a trailing slash, e.g. `codegolf.meta.stackexchange.com`
+    pub fn host(&self) -> &str {
+        let url = self.url.trim_end_matches('/');
+
+        url.split_once("://").map_or(url, |(_, host)| host)
+    }
+
+    /// `path` on the site, e.g. `page("/q/1")`
+    pub fn page(&self, path: &str) -> String {
+        format!("{}{}", self.url.trim_end_matches('/'), path)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserConfig {
-    pub login_site: String,
+    /// The site to log in through, as a host such as `codegolf.stackexchange.com` or a URL; defaults to
+    /// `endpoints.siteUrl`
+    #[serde(default)]
+    pub login_site: Option<String>,
     pub email: String,
+    #[serde(skip_serializing)]
     pub password: String,
 }
 
-#[derive(Deserialize)]
+impl UserConfig {
+    pub fn login_url(&self, endpoints: &EndpointsConfig) -> String {
+        self.login_site.as_deref().map_or_else(|| endpoints.site_url.clone(), origin)
+    }
+}
+
+// A bare host is taken to be on HTTPS
+fn origin(host_or_url: &str) -> String {
+    if host_or_url.contains("://") {
+        host_or_url.to_owned()
+    } else {
+        format!("https://{}", host_or_url)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct RoomConfig {
-    pub server: String,
+    /// The chat server the room is on, as a host such as `chat.stackexchange.com` or a URL; defaults to
+    /// `endpoints.chatServer`
+    #[serde(default)]
+    pub server: Option<String>,
     pub id: String,
 }
 
-#[derive(Deserialize)]
+impl RoomConfig {
+    pub fn server_url(&self, endpoints: &EndpointsConfig) -> String {
+        self.server.as_deref().map_or_else(|| endpoints.chat_server.clone(), origin)
+    }
+
+    pub fn room_id(&self) -> u64 {
+        // Checked when linking
+        self.id.parse().unwrap()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct WatchSocketConfig {
     pub site: String,
+    #[serde(default)]
+    pub source: WatchSource,
     #[serde(flatten)]
     pub config: WatchSocketConfigType,
 }
 
-#[derive(Deserialize)]
+/// Where a watch socket's posts come from.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum WatchSource {
+    /// qa.sockets, falling back to polling the API while it's unreachable or silent
+    #[default]
+    Auto,
+    /// qa.sockets only
+    Socket,
+    /// The API only
+    Poll,
+}
+
+#[derive(Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum WatchSocketConfigType {
     Questions,
     Answers {
         question_id: String,
+    },
+    /// Newest questions with a tag
+    Tag {
+        tag: String,
+    },
+    /// The network-wide `155-questions-active` feed, narrowed to the watch socket's site and, if any are given, to
+    /// questions with at least one of `tags`
+    Active {
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+    /// Activity on a single question and its answers
+    QuestionActivity {
+        question_id: String,
+        #[serde(default = "QuestionEvent::defaults")]
+        events: Vec<QuestionEvent>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum QuestionEvent {
+    AnswerAdd,
+    PostEdit,
+    CommentAdd,
+    Score,
+    Close,
+    Reopen,
+}
+
+impl QuestionEvent {
+    // Score changes are too chatty to announce unless asked for
+    fn defaults() -> Vec<QuestionEvent> {
+        vec![QuestionEvent::AnswerAdd, QuestionEvent::PostEdit, QuestionEvent::CommentAdd, QuestionEvent::Close, QuestionEvent::Reopen]
+    }
+}
+
+/// How redundant connections of one kind overlap.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct RotationConfig {
+    /// Connections kept open at once, for chat per user and room
+    pub replicas: usize,
+    /// How long each connection lives before it's replaced
+    pub lifetime_ms: u64,
+    /// How far apart the connections sharing a rotation are replaced; defaults to spreading them evenly over the lifetime
+    #[serde(default)]
+    pub stagger_ms: Option<u64>,
+    /// The same event from several connections within this long is only handled once
+    #[serde(default = "RotationConfig::default_dedup_window_ms")]
+    pub dedup_window_ms: u64,
+}
+
+impl RotationConfig {
+    fn default_dedup_window_ms() -> u64 {
+        60000
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RotationsConfig {
+    #[serde(default = "RotationsConfig::default_watch")]
+    pub watch: RotationConfig,
+    #[serde(default = "RotationsConfig::default_chat")]
+    pub chat: RotationConfig,
+}
+
+impl RotationsConfig {
+    fn default_watch() -> RotationConfig {
+        RotationConfig {
+            replicas: 2,
+            lifetime_ms: 1440000,
+            stagger_ms: None,
+            dedup_window_ms: RotationConfig::default_dedup_window_ms(),
+        }
+    }
+
+    fn default_chat() -> RotationConfig {
+        RotationConfig {
+            replicas: 1,
+            lifetime_ms: 7200000,
+            stagger_ms: None,
+            dedup_window_ms: RotationConfig::default_dedup_window_ms(),
+        }
+    }
+}
+
+impl Default for RotationsConfig {
+    fn default() -> RotationsConfig {
+        RotationsConfig {
+            watch: RotationsConfig::default_watch(),
+            chat: RotationsConfig::default_chat(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PollingConfig {
+    /// Polling speeds up towards this while posts keep turning up
+    #[serde(default = "PollingConfig::default_min_interval_ms")]
+    pub min_interval_ms: u64,
+    /// ...and slows down towards this while they don't
+    #[serde(default = "PollingConfig::default_max_interval_ms")]
+    pub max_interval_ms: u64,
+    /// `auto` watch sockets start polling once their feed on qa.sockets has had no events for this long
+    #[serde(default = "PollingConfig::default_silence_ms")]
+    pub silence_ms: u64,
+    /// Below this much remaining API quota, polling runs at `max_interval_ms`
+    #[serde(default = "PollingConfig::default_low_quota")]
+    pub low_quota: u64,
+}
+
+impl PollingConfig {
+    fn default_min_interval_ms() -> u64 {
+        15000
+    }
+
+    fn default_max_interval_ms() -> u64 {
+        300000
+    }
+
+    fn default_silence_ms() -> u64 {
+        600000
+    }
+
+    fn default_low_quota() -> u64 {
+        1000
+    }
+}
+
+impl Default for PollingConfig {
+    fn default() -> PollingConfig {
+        PollingConfig {
+            min_interval_ms: PollingConfig::default_min_interval_ms(),
+            max_interval_ms: PollingConfig::default_max_interval_ms(),
+            silence_ms: PollingConfig::default_silence_ms(),
+            low_quota: PollingConfig::default_low_quota(),
+        }
+    }
+}
+
+/// Limits on how hard the bot uses the Stack Exchange API, shared by every caller.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiConfig {
+    #[serde(default = "ApiConfig::default_max_requests_per_second")]
+    pub max_requests_per_second: f64,
+    /// A warning is logged once the day's remaining quota drops below this
+    #[serde(default = "ApiConfig::default_warn_quota")]
+    pub warn_quota: u64,
+    /// Below this, non-essential calls (reconciliation, comment authors, site checks) are skipped
+    #[serde(default = "ApiConfig::default_shed_quota")]
+    pub shed_quota: u64,
+}
+
+impl ApiConfig {
+    fn default_max_requests_per_second() -> f64 {
+        5.0
+    }
+
+    fn default_warn_quota() -> u64 {
+        2000
+    }
+
+    fn default_shed_quota() -> u64 {
+        1000
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> ApiConfig {
+        ApiConfig {
+            max_requests_per_second: ApiConfig::default_max_requests_per_second(),
+            warn_quota: ApiConfig::default_warn_quota(),
+            shed_quota: ApiConfig::default_shed_quota(),
+        }
+    }
+}
+
+/// How long to wait for a post from the socket to show up on the API before announcing it.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WaitForApiConfig {
+    #[serde(default = "WaitForApiConfig::default_attempts")]
+    pub attempts: u32,
+    /// The delay between attempts starts here and doubles each time...
+    #[serde(default = "WaitForApiConfig::default_initial_delay_ms")]
+    pub initial_delay_ms: u64,
+    /// ...up to this
+    #[serde(default = "WaitForApiConfig::default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// No attempt is started after this long, however many are left
+    #[serde(default = "WaitForApiConfig::default_deadline_ms")]
+    pub deadline_ms: u64,
+    #[serde(default)]
+    pub on_missing: MissingPost,
+    /// How long `retryLater` waits before trying again, once
+    #[serde(default = "WaitForApiConfig::default_retry_later_ms")]
+    pub retry_later_ms: u64,
+}
+
+impl WaitForApiConfig {
+    fn default_attempts() -> u32 {
+        8
+    }
+
+    fn default_initial_delay_ms() -> u64 {
+        200
+    }
+
+    fn default_max_delay_ms() -> u64 {
+        2000
+    }
+
+    fn default_deadline_ms() -> u64 {
+        15000
+    }
+
+    fn default_retry_later_ms() -> u64 {
+        300000
+    }
+}
+
+impl Default for WaitForApiConfig {
+    fn default() -> WaitForApiConfig {
+        WaitForApiConfig {
+            attempts: WaitForApiConfig::default_attempts(),
+            initial_delay_ms: WaitForApiConfig::default_initial_delay_ms(),
+            max_delay_ms: WaitForApiConfig::default_max_delay_ms(),
+            deadline_ms: WaitForApiConfig::default_deadline_ms(),
+            on_missing: MissingPost::default(),
+            retry_later_ms: WaitForApiConfig::default_retry_later_ms(),
+        }
+    }
+}
+
+/// What to do with a post that never showed up on the API.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum MissingPost {
+    /// Announce it anyway, without the checks that need its metadata
+    #[default]
+    Post,
+    Drop,
+    /// Wait `retryLaterMs` and go through the policy again, dropping it if it's still missing
+    RetryLater,
+}
+
+/// Where logs go and what they look like.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoggingConfig {
+    /// Which events to log, as `RUST_LOG`-style directives such as `info` or `info,npsp::api=debug`; `RUST_LOG`
+    /// overrides it
+    #[serde(default = "LoggingConfig::default_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Also log to rotating files, besides stdout
+    #[serde(default)]
+    pub file: Option<LogFileConfig>,
+}
+
+impl LoggingConfig {
+    fn default_level() -> String {
+        "info".to_owned()
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            level: LoggingConfig::default_level(),
+            format: LogFormat::default(),
+            file: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum LogFormat {
+    /// One human-readable line per event
+    #[default]
+    Text,
+    /// One JSON object per line, with every field of the event and the spans it happened in
+    Json,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogFileConfig {
+    pub directory: String,
+    /// File names are this followed by the date, e.g. `npsp.log.2024-01-31`
+    #[serde(default = "LogFileConfig::default_prefix")]
+    pub prefix: String,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// Older files are deleted once there are more than this; all are kept if unset
+    #[serde(default)]
+    pub max_files: Option<usize>,
+}
+
+impl LogFileConfig {
+    fn default_prefix() -> String {
+        "npsp.log".to_owned()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+/// The Prometheus endpoint, served at `/metrics`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsConfig {
+    #[serde(default = "MetricsConfig::default_listen")]
+    pub listen: String,
+}
+
+impl MetricsConfig {
+    fn default_listen() -> String {
+        "127.0.0.1:9184".to_owned()
+    }
+}
+
+/// The admin API, which can change what the bot does, so it's only ever served locally.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminConfig {
+    /// A loopback `host:port`, or `unix:/path/to/socket`
+    #[serde(default = "AdminConfig::default_listen")]
+    pub listen: String,
+    /// Sent by clients as `Authorization: Bearer …`
+    #[serde(skip_serializing)]
+    pub token: String,
+}
+
+impl AdminConfig {
+    fn default_listen() -> String {
+        "127.0.0.1:9185".to_owned()
+    }
+}
+
+/// What happens on SIGTERM or SIGINT.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShutdownConfig {
+    /// How long tasks get to stop and the chat queue gets to drain before state is saved and the process exits
+    #[serde(default = "ShutdownConfig::default_deadline_ms")]
+    pub deadline_ms: u64,
+}
+
+impl ShutdownConfig {
+    fn default_deadline_ms() -> u64 {
+        10000
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> ShutdownConfig {
+        ShutdownConfig {
+            deadline_ms: ShutdownConfig::default_deadline_ms(),
+        }
+    }
+}
+
+/// Where Stack Exchange is, for pointing the bot at a local stand-in or a proxy instead.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointsConfig {
+    /// The site users log in through, unless they have a `loginSite`
+    #[serde(default = "EndpointsConfig::default_site_url")]
+    pub site_url: String,
+    /// The chat server rooms are on, unless they have a `server`
+    #[serde(default = "EndpointsConfig::default_chat_server")]
+    pub chat_server: String,
+    #[serde(default = "EndpointsConfig::default_api_root")]
+    pub api_root: String,
+    /// qa.sockets
+    #[serde(default = "EndpointsConfig::default_socket_url")]
+    pub socket_url: String,
+}
+
+impl EndpointsConfig {
+    fn default_site_url() -> String {
+        "https://codegolf.stackexchange.com".to_owned()
+    }
+
+    fn default_chat_server() -> String {
+        "https://chat.stackexchange.com".to_owned()
+    }
+
+    fn default_api_root() -> String {
+        "https://api.stackexchange.com/2.3".to_owned()
+    }
+
+    fn default_socket_url() -> String {
+        "wss://qa.sockets.stackexchange.com/".to_owned()
+    }
+
+    /// `path` on the login site, e.g. `site("/users/login")`
+    pub fn site(&self, path: &str) -> String {
+        format!("{}{}", self.site_url.trim_end_matches('/'), path)
+    }
+
+    pub fn chat(&self, path: &str) -> String {
+        format!("{}{}", self.chat_server.trim_end_matches('/'), path)
     }
 }
 
+impl Default for EndpointsConfig {
+    fn default() -> EndpointsConfig {
+        EndpointsConfig {
+            site_url: EndpointsConfig::default_site_url(),
+            chat_server: EndpointsConfig::default_chat_server(),
+            api_root: EndpointsConfig::default_api_root(),
+            socket_url: EndpointsConfig::default_socket_url(),
+        }
+    }
+}
+
+/// How far catch-up after an outage reaches back.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackfillConfig {
+    /// Posts older than this are handled according to `overflow` instead of being announced one by one
+    #[serde(default = "BackfillConfig::default_max_age_ms")]
+    pub max_age_ms: u64,
+    #[serde(default)]
+    pub overflow: BackfillOverflow,
+}
+
+impl BackfillConfig {
+    fn default_max_age_ms() -> u64 {
+        21600000
+    }
+}
+
+impl Default for BackfillConfig {
+    fn default() -> BackfillConfig {
+        BackfillConfig {
+            max_age_ms: BackfillConfig::default_max_age_ms(),
+            overflow: BackfillOverflow::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum BackfillOverflow {
+    /// One message per route saying how many posts were missed, with a link to the feed
+    #[default]
+    Summarise,
+    /// Only logged
+    Skip,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconcileConfig {
+    /// How often each route's feed is compared against the posts it already knows about
+    pub interval_ms: u64,
+    /// Only log missed posts, rather than announcing them
+    #[serde(default)]
+    pub report_only: bool,
+}
+
 pub struct RouteConfig<'a> {
+    pub id: &'a str,
+    pub user_id: &'a str,
     pub user: &'a UserConfig,
+    pub watch_socket_id: &'a str,
     pub watch_socket: &'a WatchSocketConfig,
+    pub site: &'a SiteConfig,
     pub room: &'a RoomConfig,
 
     pub force_user_client_for_watch_socket: bool,
+    /// Links queued within this long of each other are announced in a single message
+    pub coalesce_window: Option<Duration>,
+    /// Messages are logged instead of posted
+    pub dry_run: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct UnlinkedRouteConfig {
     user: String,
@@ -143,4 +955,8 @@ struct UnlinkedRouteConfig {
 
     #[serde(default)]
     force_user_client_for_watch_socket: bool,
+    #[serde(default)]
+    coalesce_window_ms: Option<u64>,
+    #[serde(default)]
+    dry_run: bool,
 }
\ No newline at end of file
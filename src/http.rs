@@ -0,0 +1,247 @@
+use std::{error::Error, fmt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::{debug, Instrument};
+
+use crate::shutdown::Shutdown;
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+// Requests are only ever small JSON bodies
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+
+// So a client that never finishes its request doesn't hold a connection open forever
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct BadRequest {
+    reason: &'static str
+}
+
+impl Error for BadRequest {}
+
+impl fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad request: {}", self.reason)
+    }
+}
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    /// Keyed by lowercased name
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+}
+
+pub struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String
+}
+
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into()
+        }
+    }
+
+    pub fn json(status: u16, body: &impl Serialize) -> Response {
+        match serde_json::to_string_pretty(body) {
+            Ok(body) => Response {
+                status,
+                content_type: "application/json",
+                body: body + "\n"
+            },
+            Err(err) => Response::error(500, &err.to_string())
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Response {
+        Response::json(status, &serde_json::json!({ "error": message }))
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            415 => "Unsupported Media Type",
+            _ => "Internal Server Error"
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener)
+}
+
+/// Binds `address`, either `host:port` or `unix:/path/to/socket`. A stale unix socket from a previous run is replaced.
+pub async fn bind(address: &str) -> Result<Listener> {
+    match address.strip_prefix("unix:") {
+        Some(path) => {
+            let _ = tokio::fs::remove_file(path).await;
+
+            Ok(Listener::Unix(UnixListener::bind(path)?))
+        }
+        None => Ok(Listener::Tcp(TcpListener::bind(address).await?))
+    }
+}
+
+async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> Result<Request> {
+    match tokio::time::timeout(READ_TIMEOUT, read_request_untimed(stream)).await {
+        Ok(request) => request,
+        Err(_) => Err(Box::new(BadRequest { reason: "timed out reading the request" }))
+    }
+}
+
+async fn read_request_untimed(stream: &mut (impl AsyncRead + Unpin)) -> Result<Request> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+
+    let header_end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+
+        let read = stream.read(&mut chunk).await?;
+
+        if read == 0 || buffer.len() + read > MAX_REQUEST_SIZE {
+            return Err(Box::new(BadRequest { reason: "incomplete or oversized headers" }));
+        }
+
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).into_owned();
+    let mut lines = head.lines();
+
+    let (method, target) = match lines.next().unwrap_or_default().split(' ').collect::<Vec<&str>>()[..] {
+        [method, target, _] => (method.to_owned(), target.to_owned()),
+        _ => return Err(Box::new(BadRequest { reason: "malformed request line" }))
+    };
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+        .collect::<HashMap<String, String>>();
+
+    let content_length = headers.get("content-length").map_or(Ok(0), |value| value.parse::<usize>())?;
+
+    if content_length > MAX_REQUEST_SIZE {
+        return Err(Box::new(BadRequest { reason: "oversized body" }));
+    }
+
+    let mut body = buffer.split_off(header_end + 4);
+
+    while body.len() < content_length {
+        let read = stream.read(&mut chunk).await?;
+
+        if read == 0 {
+            return Err(Box::new(BadRequest { reason: "incomplete body" }));
+        }
+
+        body.extend_from_slice(&chunk[..read]);
+    }
+
+    body.truncate(content_length);
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+
+    Ok(Request {
+        method,
+        path: path.to_owned(),
+        query: url::form_urlencoded::parse(query.as_bytes()).into_owned().collect(),
+        headers,
+        body
+    })
+}
+
+async fn handle_connection<S, F, Fut>(mut stream: S, handler: Arc<F>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Response>
+{
+    let response = match read_request(&mut stream).await {
+        Ok(request) => handler(request).await,
+        Err(err) => Response::error(400, &err.to_string())
+    };
+
+    let head = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", response.status, response.reason(), response.content_type, response.body.len());
+
+    if let Err(err) = async {
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(response.body.as_bytes()).await?;
+        stream.shutdown().await
+    }.await {
+        debug!(%err, "failed to write response");
+    }
+}
+
+/// Answers each request on `listener` with `handler`, one request per connection, until `shutdown`. Only as much HTTP/1.1
+/// as local tools like curl and Prometheus need is spoken.
+pub async fn serve<F, Fut>(listener: Listener, shutdown: &Shutdown, handler: F) -> Result<()>
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static
+{
+    let handler = Arc::new(handler);
+
+    loop {
+        match &listener {
+            Listener::Tcp(listener) => {
+                let (stream, _) = match shutdown.until(listener.accept()).await {
+                    Some(accepted) => accepted?,
+                    None => return Ok(())
+                };
+
+                tokio::spawn(handle_connection(stream, Arc::clone(&handler)).in_current_span());
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = match shutdown.until(listener.accept()).await {
+                    Some(accepted) => accepted?,
+                    None => return Ok(())
+                };
+
+                tokio::spawn(handle_connection(stream, Arc::clone(&handler)).in_current_span());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_headers_case_insensitively() {
+        let mut raw: &[u8] = b"POST /rooms/240/messages?x=1 HTTP/1.1\r\nHost: 127.0.0.1:9185\r\ncontent-type: application/json\r\nContent-Length: 2\r\n\r\n{}";
+
+        let request = read_request(&mut raw).await.unwrap();
+
+        assert_eq!(request.path, "/rooms/240/messages");
+        assert_eq!(request.header("host"), Some("127.0.0.1:9185"));
+        assert_eq!(request.header("Content-Type"), Some("application/json"));
+        assert_eq!(request.body, b"{}");
+    }
+}
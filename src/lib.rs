@@ -0,0 +1,396 @@
+//! NPSP posts new Stack Exchange questions, answers and other activity to chat rooms.
+//!
+//! [`run`] is the whole bot, as the `npsp` binary runs it. The plumbing underneath is public for other bots to build
+//! on:
+//!
+//! - [`config`] loads and checks `config.json`
+//! - [`login`] logs users in, giving a [`login::User`] with an authenticated client and chat fkey
+//! - [`chat`] posts to rooms, reads their history and opens their websockets
+//! - [`socket`] subscribes to qa.sockets and decodes what it sends
+//! - [`api`] calls the Stack Exchange API within its rate limits and quota
+//!
+//! ```no_run
+//! use npsp::{config::UnlinkedConfig, login, chat::{self, PostOutcome}};
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//! let config = UnlinkedConfig::load("config.json")?.link()?;
+//! let user = login::log_in("np", &config.get_users()["np"], &config.get_user_endpoints("np"), config.get_state_dir()).await?;
+//!
+//! if let PostOutcome::Failed(err) = chat::post(240, "hello", &user).await {
+//!     return Err(err);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod login;
+pub mod api;
+pub mod chat;
+pub mod config;
+pub mod socket;
+mod watch;
+mod queue;
+mod retry;
+mod rotate;
+mod poll;
+mod reconcile;
+mod supervisor;
+mod scheduler;
+mod logging;
+mod metrics;
+mod http;
+mod admin;
+mod shutdown;
+mod capture;
+mod replay;
+
+use config::{Config, UnlinkedConfig};
+use queue::MessageQueue;
+use supervisor::Supervisor;
+use login::User;
+use watch::Watcher;
+use chat::ChatRoom;
+use rotate::Rotation;
+use scheduler::Scheduler;
+
+pub use shutdown::Shutdown;
+
+use std::sync::Arc;
+use std::path::Path;
+use tokio::sync::Mutex;
+use std::collections::{HashSet, HashMap, BTreeMap, BTreeSet};
+use std::time::{Duration, SystemTime};
+use serde::{Serialize, Deserialize};
+use tracing::{info, warn};
+
+const TMP_FILE_REVISION: &str = "0";
+const IDS_FILE: &str = "ids.json";
+
+/// Milliseconds since the epoch, or the virtual clock's idea of it while replaying a capture.
+pub fn time() -> u128 {
+    replay::virtual_time().unwrap_or_else(|| SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis())
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedIds {
+    revision: String,
+    rooms: Vec<(u64, String, Vec<String>)>
+}
+
+/// Post IDs known to have been linked in each room, per site, so they're only posted once.
+///
+/// Chat history is searched for them on every start anyway, but IDs saved on shutdown also cover links that have
+/// scrolled out of it.
+#[derive(Default)]
+pub(crate) struct Ids {
+    rooms: HashMap<(u64, String), HashSet<String>>
+}
+
+impl Ids {
+    /// Reads `ids.json` from the state directory, if it's there and from this revision.
+    pub fn load(state_dir: &Path) -> Ids {
+        let saved = match std::fs::read_to_string(state_dir.join(IDS_FILE)) {
+            Ok(json) => serde_json::from_str::<SavedIds>(&json).ok().filter(|saved| saved.revision == TMP_FILE_REVISION),
+            Err(_) => None
+        };
+        
+        let mut ids = Ids::default();
+        
+        for (room_id, site, post_ids) in saved.map_or_else(Vec::new, |saved| saved.rooms) {
+            for post_id in post_ids {
+                ids.insert(room_id, &site, &post_id);
+            }
+        }
+        
+        ids
+    }
+    
+    pub fn save(&self, state_dir: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let saved = SavedIds {
+            revision: TMP_FILE_REVISION.to_string(),
+            rooms: self.rooms.iter().map(|((room_id, site), ids)| (*room_id, site.clone(), ids.iter().cloned().collect())).collect()
+        };
+        
+        std::fs::create_dir_all(state_dir)?;
+        std::fs::write(state_dir.join(IDS_FILE), serde_json::to_string(&saved)?)?;
+        
+        Ok(())
+    }
+    
+    /// Returns whether `post_id` is new to the room.
+    pub fn insert(&mut self, room_id: u64, site: &str, post_id: &str) -> bool {
+        let ids = self.rooms.entry((room_id, site.to_owned())).or_default();
+
+        let new = ids.insert(post_id.to_owned());
+
+        if new {
+            metrics::set("npsp_known_ids", &[("room", &room_id.to_string()), ("site", site)], ids.len() as f64);
+        }
+
+        new
+    }
+
+    /// Returns whether `post_id` was known to the room.
+    pub fn remove(&mut self, room_id: u64, site: &str, post_id: &str) -> bool {
+        let ids = match self.rooms.get_mut(&(room_id, site.to_owned())) {
+            Some(ids) => ids,
+            None => return false
+        };
+
+        let removed = ids.remove(post_id);
+
+        if removed {
+            metrics::set("npsp_known_ids", &[("room", &room_id.to_string()), ("site", site)], ids.len() as f64);
+        }
+
+        removed
+    }
+
+    /// How many IDs are known per room and site.
+    pub fn counts(&self) -> BTreeMap<(u64, String), usize> {
+        self.rooms.iter().map(|(key, ids)| (key.clone(), ids.len())).collect()
+    }
+
+    pub fn get(&self, room_id: u64, site: &str) -> BTreeSet<String> {
+        self.rooms.get(&(room_id, site.to_owned())).map_or_else(BTreeSet::new, |ids| ids.iter().cloned().collect())
+    }
+}
+
+/// How [`run`] differs from a normal start.
+#[derive(Default)]
+pub struct Options {
+    /// The config to run with, instead of reading `config.json` from the working directory
+    pub config: Option<UnlinkedConfig>,
+    /// Chat messages are only logged
+    pub dry_run: bool,
+    /// Where to write a capture of everything received
+    pub record: Option<String>,
+    /// A capture to replay instead of connecting to anything. It plays out on tokio's virtual clock, so the runtime
+    /// has to be current-thread and start paused, which needs tokio's `test-util` feature; the `replay` feature turns
+    /// it on.
+    pub replay: Option<String>,
+    /// Stops this run when requested, as SIGTERM or SIGINT do; keep a clone to stop it from outside
+    pub shutdown: Shutdown
+}
+
+/// Runs the bot until it's shut down by a signal or `options.shutdown`, or every task has given up.
+pub async fn run(options: Options) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = match options.config {
+        Some(config) => config,
+        None => UnlinkedConfig::load("config.json")?
+    };
+    
+    // A replay can't post anything, having never logged in
+    if options.dry_run || options.replay.is_some() {
+        config.force_dry_run();
+    }
+    
+    let capture = match &options.replay {
+        Some(path) => {
+            // State is still saved as usual, so it goes somewhere it can't clobber the live bot's
+            config.set_state_dir(std::env::temp_dir().join(format!("npsp-replay-{}", std::process::id())));
+            
+            Some(replay::load(path)?)
+        }
+        None => None
+    };
+    
+    let config: Arc<Config> = Arc::new(config.link()?);
+    
+    // A replay only logs to stdout, rather than into the live bot's log files
+    let _log_guard = logging::init(config.get_logging(), capture.is_none())?;
+    
+    if let Some(path) = &options.replay {
+        info!(capture = %path, scratch = %config.get_state_dir().display(), "replaying a capture");
+    } else if config.get_dry_run() {
+        info!("dry run: chat messages will be logged, not posted");
+    }
+    
+    if let Some(path) = &options.record {
+        capture::start_recording(path)?;
+        
+        info!(capture = %path, "recording");
+    }
+    
+    let mut users: HashMap<String, Arc<User>> = HashMap::new();
+    
+    for route in config.get_route_configs().values() {
+        if !users.contains_key(route.user_id) {
+            let user = match capture {
+                Some(_) => login::offline(route.user_id, &config.get_user_endpoints(route.user_id))?,
+                None => login::log_in(route.user_id, route.user, &config.get_user_endpoints(route.user_id), config.get_state_dir()).await?
+            };
+            
+            users.insert(route.user_id.to_owned(), Arc::new(user));
+        }
+    }
+    
+    let shutdown = options.shutdown;
+    
+    let queue = MessageQueue::load(users.clone(), config.get_state_dir(), config.get_dry_run(), shutdown.clone()).await?;
+    
+    let ids = Arc::new(Mutex::new(Ids::load(config.get_state_dir())));
+    
+    // Every user posting to a room keeps a chat connection there, to acknowledge their own mentions
+    let mut room_users: BTreeMap<u64, BTreeSet<&str>> = BTreeMap::new();
+    
+    for route in config.get_route_configs().values() {
+        room_users.entry(route.room.room_id()).or_default().insert(route.user_id);
+    }
+    
+    // Chat history isn't part of captures
+    if capture.is_none() {
+        for (room_id, user_ids) in &room_users {
+            chat::find_known_ids(*room_id, &config, Arc::clone(&users[*user_ids.first().unwrap()]), Arc::clone(&ids)).await?;
+        }
+    }
+    
+    let scheduler = Scheduler::load(config.get_state_dir());
+    
+    let watcher = Watcher::new(Arc::clone(&config), users.clone(), Arc::clone(&ids), Arc::clone(&queue), Arc::clone(&scheduler), shutdown.clone())?;
+    
+    watcher.restore_scheduled().await;
+    watcher.check_sites().await;
+    
+    let supervisor = Supervisor::new(shutdown.clone());
+    
+    // Both would compete with the live bot for its ports or admin socket
+    if capture.is_none() && config.get_metrics().is_some() {
+        let (config, shutdown) = (Arc::clone(&config), shutdown.clone());
+        
+        supervisor.spawn("metrics", move || {
+            let (config, shutdown) = (Arc::clone(&config), shutdown.clone());
+            
+            async move { metrics::serve(config.get_metrics().unwrap(), shutdown).await }
+        }).await;
+    }
+    
+    if capture.is_none() && config.get_admin().is_some() {
+        let admin = admin::Admin::new(Arc::clone(&config), users.clone(), Arc::clone(&ids), Arc::clone(&queue), Arc::clone(&watcher), Arc::clone(&supervisor));
+        
+        let shutdown = shutdown.clone();
+        
+        supervisor.spawn("admin", move || admin::serve(Arc::clone(&admin), shutdown.clone())).await;
+    }
+    
+    {
+        let watcher = Arc::clone(&watcher);
+        
+        let scheduler = Arc::clone(&scheduler);
+        
+        supervisor.spawn("scheduler", move || Arc::clone(&scheduler).run(Arc::clone(&watcher))).await;
+    }
+    
+    // Only needed if some watch socket isn't polled exclusively, and not at all when replaying
+    if capture.is_none() && watcher.has_socket_actions() {
+        for id in 0..config.get_rotation().watch.replicas {
+            let watcher = Arc::clone(&watcher);
+            
+            supervisor.spawn(&format!("watch_{}", id), move || watch::watch_ws(id, Arc::clone(&watcher))).await;
+        }
+    }
+    
+    for watch_socket_id in watcher.polled_watch_sockets() {
+        let watcher = Arc::clone(&watcher);
+        
+        supervisor.spawn(&format!("poll-{}", watch_socket_id), move || poll::poll(Arc::clone(&watcher), watch_socket_id.clone())).await;
+    }
+    
+    if config.get_reconcile().is_some() {
+        for route_id in config.get_route_configs().keys() {
+            let (watcher, route_id) = (Arc::clone(&watcher), route_id.to_string());
+            
+            supervisor.spawn(&format!("reconcile-{}", route_id), move || reconcile::reconcile(Arc::clone(&watcher), route_id.clone())).await;
+        }
+    }
+    
+    let chat_rotation = config.get_rotation().chat;
+    
+    let mut rooms = HashMap::new();
+    
+    for (room_id, user_ids) in room_users {
+        // Users in the same room share a rotation, so their connections are staggered against each other too
+        let rotation = Rotation::new(&chat_rotation, user_ids.len() * chat_rotation.replicas, shutdown.clone());
+        
+        for (index, user_id) in user_ids.into_iter().enumerate() {
+            let room = ChatRoom::new(room_id, Arc::clone(&users[user_id]), Arc::clone(&ids), Arc::clone(&config), Arc::clone(&rotation), index * chat_rotation.replicas, shutdown.clone());
+            
+            if capture.is_some() {
+                rooms.insert((room_id, user_id.to_owned()), room);
+                
+                continue;
+            }
+            
+            for replica in 0..chat_rotation.replicas {
+                let room = Arc::clone(&room);
+                
+                supervisor.spawn(&format!("{}-{}_{}", user_id, room_id, replica), move || chat::chat_ws(Arc::clone(&room), replica)).await;
+            }
+        }
+    }
+    
+    if let Some(capture) = capture {
+        tokio::spawn(replay::run(capture, Arc::clone(&watcher), rooms, Arc::clone(&queue)));
+    }
+    
+    let signals = {
+        let shutdown = shutdown.clone();
+        
+        tokio::spawn(async move {
+            match shutdown::signal_received().await {
+                Ok(()) => {
+                    info!("shutting down");
+                    
+                    shutdown.request();
+                }
+                Err(err) => warn!(%err, "can't listen for signals; shutdown won't be graceful")
+            }
+        })
+    };
+    
+    supervisor.report();
+    
+    let deadline = Duration::from_millis(config.get_shutdown().deadline_ms);
+    
+    let join = supervisor.join();
+    
+    tokio::pin!(join);
+    
+    // Tasks only stop for good once shutdown is requested, unless every one of them gives up first
+    let joined = tokio::select! {
+        _ = &mut join => true,
+        _ = shutdown.requested() => false
+    };
+    
+    shutdown.request();
+    
+    signals.abort();
+    
+    // The queue is on disk after every change, so anything left when the deadline passes goes out on the next start
+    if tokio::time::timeout(deadline, async {
+        if !joined {
+            join.await;
+        }
+        
+        queue.drain().await;
+    }).await.is_err() {
+        warn!("didn't finish stopping tasks and draining the queue before the deadline");
+    }
+    
+    scheduler.flush().await;
+    
+    if let Err(err) = ids.lock().await.save(config.get_state_dir()) {
+        warn!(%err, "failed to save known IDs");
+    }
+    
+    // Offline users have nothing worth saving
+    for user in users.values().filter(|_| !replay::is_replaying()) {
+        if let Err(err) = user.save_cookies(config.get_state_dir()) {
+            warn!(user = %user.id, %err, "failed to save cookies");
+        }
+    }
+    
+    info!("state saved, exiting");
+    
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use std::error::Error;
+use tracing_appender::{non_blocking::WorkerGuard, rolling::{RollingFileAppender, Rotation}};
+use tracing_subscriber::{fmt, prelude::*, EnvFilter, Layer};
+
+use crate::config::{LoggingConfig, LogFormat, LogRotation};
+
+/// Sets up the global logger: stdout, plus rotating files if configured and `files` is set. A subscriber that's already
+/// set, say by a program running the bot as a library, is left alone.
+///
+/// File output is written from a background thread; the returned guard flushes it when dropped, so it has to be kept
+/// until the process exits.
+pub fn init(config: &LoggingConfig, files: bool) -> Result<Option<WorkerGuard>, Box<dyn Error + Send + Sync>> {
+    if tracing::dispatcher::has_been_set() {
+        return Ok(None);
+    }
+
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&config.level)?
+    };
+
+    let stdout = match config.format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Json => fmt::layer().json().with_span_list(true).boxed()
+    };
+
+    let (file, guard) = match config.file.as_ref().filter(|_| files) {
+        Some(file) => {
+            // Old files are pruned as soon as the appender is built, which fails if there's no directory yet
+            std::fs::create_dir_all(&file.directory)?;
+
+            let mut appender = RollingFileAppender::builder()
+                .rotation(match file.rotation {
+                    LogRotation::Hourly => Rotation::HOURLY,
+                    LogRotation::Daily => Rotation::DAILY,
+                    LogRotation::Never => Rotation::NEVER
+                })
+                .filename_prefix(&file.prefix);
+
+            if let Some(max_files) = file.max_files {
+                appender = appender.max_log_files(max_files);
+            }
+
+            let (writer, guard) = tracing_appender::non_blocking(appender.build(&file.directory)?);
+
+            let layer = match config.format {
+                LogFormat::Text => fmt::layer().with_ansi(false).with_writer(writer).boxed(),
+                LogFormat::Json => fmt::layer().json().with_span_list(true).with_writer(writer).boxed()
+            };
+
+            (Some(layer), Some(guard))
+        }
+        None => (None, None)
+    };
+
+    tracing_subscriber::registry().with(stdout).with(file).with(filter).try_init()?;
+
+    Ok(guard)
+}
@@ -1,13 +1,17 @@
 use std::{error::Error, fmt};
 use std::sync::Arc;
+use std::path::Path;
 use html_parser::{Dom, Node};
 use serde::{Serialize, Deserialize};
+use tracing::{info, debug};
 
-use crate::config::UserConfig;
+use crate::config::{UserConfig, EndpointsConfig};
 use crate::{time, TMP_FILE_REVISION};
 
 type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
 
+pub const USER_AGENT: &str = "Mozilla/5.0 (compatible; NPSP/2.0; +https://chat.stackexchange.com/rooms/240/the-nineteenth-byte)";
+
 #[derive(Debug)]
 struct MissingFkey {}
 
@@ -47,11 +51,11 @@ fn extract_fkey(html: &str) -> Result<String> {
     fn search_node(node: &Node) -> Option<String> {
         match node {
             Node::Element(element) => {
-                if element.name == "input" && element.attributes.get("name").map_or(false, |attr| attr.as_ref().map_or(false, |name| name == "fkey")) {
+                if element.name == "input" && element.attributes.get("name").is_some_and(|attr| attr.as_ref().is_some_and(|name| name == "fkey")) {
                     element.attributes.get("value").map(|value| value.clone().unwrap_or("".to_owned()))
                 } else {
                     for child in &element.children {
-                        let result = search_node(&child);
+                        let result = search_node(child);
                         
                         if result.is_some() {
                             return result;
@@ -82,7 +86,7 @@ fn contains_logout(html: &str) -> Result<bool> {
     fn search_node(node: &Node) -> bool {
         match node {
             Node::Element(element) => {
-                if element.name == "a" && element.attributes.get("href").map_or(false, |attr| attr.as_ref().map_or(false, |href| href.ends_with("logout"))) {
+                if element.name == "a" && element.attributes.get("href").is_some_and(|attr| attr.as_ref().is_some_and(|href| href.ends_with("logout"))) {
                     true
                 } else {
                     element.children.iter().any(search_node)
@@ -101,11 +105,11 @@ fn extract_user_id(html: &str) -> Result<String> {
     fn search_node(node: &Node) -> Option<String> {
         match node {
             Node::Element(element) => {
-                if element.name == "a" && element.attributes.get("href").map_or(false, |attr| attr.as_ref().map_or(false, |href| href.starts_with("/users/"))) {
+                if element.name == "a" && element.attributes.get("href").is_some_and(|attr| attr.as_ref().is_some_and(|href| href.starts_with("/users/"))) {
                     Some(element.attributes.get("href").unwrap().as_ref().unwrap()[7..].split('/').next().unwrap().to_owned())
                 } else {
                     for child in &element.children {
-                        let result = search_node(&child);
+                        let result = search_node(child);
                         
                         if result.is_some() {
                             return result;
@@ -122,7 +126,7 @@ fn extract_user_id(html: &str) -> Result<String> {
     let dom = Dom::parse(html)?;
     
     for child in &dom.children {
-        let result = search_node(&child);
+        let result = search_node(child);
 
         if let Some(user_id) = result {
             return Ok(user_id);
@@ -132,10 +136,10 @@ fn extract_user_id(html: &str) -> Result<String> {
     Err(Box::new(MissingUserId {})) // research
 }
 
-async fn try_login(client: &reqwest::Client, email: &str, password: &str) -> Result<Credentials> {
-    let fkey = extract_fkey(&client.get("https://codegolf.stackexchange.com/users/login").send().await?.error_for_status()?.text().await?)?;
+async fn try_login(client: &reqwest::Client, endpoints: &EndpointsConfig, email: &str, password: &str) -> Result<Credentials> {
+    let fkey = extract_fkey(&client.get(endpoints.site("/users/login")).send().await?.error_for_status()?.text().await?)?;
     
-    let is_login_ok = client.post("https://codegolf.stackexchange.com/users/login-or-signup/validation/track").form(&[
+    let is_login_ok = client.post(endpoints.site("/users/login-or-signup/validation/track")).form(&[
         ("email", email),
         ("password", password),
         ("isSignup", "false"),
@@ -149,14 +153,17 @@ async fn try_login(client: &reqwest::Client, email: &str, password: &str) -> Res
     ]).send().await?.error_for_status()?.text().await?;
     
     if is_login_ok != "Login-OK" {
-        dbg!(is_login_ok);
+        debug!(response = %is_login_ok, "login rejected");
         
         return Err(Box::new(LoginError {
             description: "No 'Login-OK'".to_owned()
         }));
     }
     
-    let login_two = client.post("https://codegolf.stackexchange.com/users/login?ssrc=head&returnurl=https%3a%2f%2fcodegolf.stackexchange.com%2f").form(&[
+    let login_two = client.post(endpoints.site("/users/login")).query(&[
+        ("ssrc", "head"),
+        ("returnurl", endpoints.site("/").as_str())
+    ]).form(&[
         ("email", email),
         ("password", password),
         ("ssrc", "head"),
@@ -164,16 +171,16 @@ async fn try_login(client: &reqwest::Client, email: &str, password: &str) -> Res
     ]).send().await?.error_for_status()?.text().await?;
     
     if !contains_logout(&login_two)? {
-        dbg!(login_two);
+        debug!(page = %login_two, "no logout link after logging in");
         
         return Err(Box::new(LoginError {
             description: "No 'logout'; possibly CAPTCHA'd".to_owned()
         }));
     }
     
-    // client.post("https://codegolf.stackexchange.com/users/login/universal/request").send().await?.error_for_status()?;
+    // client.post(endpoints.site("/users/login/universal/request")).send().await?.error_for_status()?;
     
-    let user = client.get("https://chat.stackexchange.com/chats/join/favorite").send().await?.error_for_status()?.text().await?;
+    let user = client.get(endpoints.chat("/chats/join/favorite")).send().await?.error_for_status()?.text().await?;
     
     let user_id = extract_user_id(&user)?;
     let logged_in_fkey = extract_fkey(&user)?;
@@ -181,7 +188,7 @@ async fn try_login(client: &reqwest::Client, email: &str, password: &str) -> Res
     Ok(Credentials {
         revision: TMP_FILE_REVISION.to_string(),
         time: time(),
-        user_id: user_id,
+        user_id,
         fkey: logged_in_fkey
     })
 }
@@ -216,8 +223,8 @@ impl fmt::Display for WrongCredentialsRevision {
     }
 }
 
-async fn retrieve_credentials(user_id: &str) -> Result<Credentials> {
-    let json = tokio::fs::read_to_string(format!("tmp/{}-credentials.json", user_id)).await?;
+async fn retrieve_credentials(user_id: &str, state_dir: &Path) -> Result<Credentials> {
+    let json = tokio::fs::read_to_string(state_dir.join(format!("{}-credentials.json", user_id))).await?;
     
     let credentials: Credentials = serde_json::from_str(&json)?;
     
@@ -231,46 +238,86 @@ async fn retrieve_credentials(user_id: &str) -> Result<Credentials> {
         return Err(Box::new(WrongCredentialsRevision {}));
     }
     
-    return Ok(credentials);
+    Ok(credentials)
 }
 
 pub struct User {
+    pub id: String,
     pub client: reqwest::Client,
-    pub fkey: String
+    pub fkey: String,
+    /// The chat server the user is logged in to, which `fkey` is for
+    chat_server: String,
+    cookie_store: Arc<reqwest_cookie_store::CookieStoreMutex>
 }
 
-pub async fn log_in(user_id: &str, user_config: &UserConfig) -> Result<User> {
-    let credentials = retrieve_credentials(user_id).await.ok();
+impl User {
+    /// `path` on the user's chat server, e.g. `chat_url("/messages/ack")`
+    pub fn chat_url(&self, path: &str) -> String {
+        format!("{}{}", self.chat_server.trim_end_matches('/'), path)
+    }
+    
+    /// Writes the cookie jar to the state directory, so the next start can reuse the session along with the saved
+    /// credentials.
+    pub fn save_cookies(&self, state_dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(state_dir)?;
+        
+        self.cookie_store.lock().unwrap().save_json(&mut std::io::BufWriter::new(std::fs::File::create(state_dir.join(format!("{}-cookies.json", self.id)))?))?;
+        
+        Ok(())
+    }
+}
+
+/// A user that never logs in, for replaying captures, which make no requests as anyone.
+pub fn offline(user_id: &str, endpoints: &EndpointsConfig) -> Result<User> {
+    let cookie_store = Arc::new(reqwest_cookie_store::CookieStoreMutex::new(reqwest_cookie_store::CookieStore::default()));
+    
+    Ok(User {
+        id: user_id.to_owned(),
+        client: reqwest::ClientBuilder::new().user_agent(USER_AGENT).cookie_provider(Arc::clone(&cookie_store)).build()?,
+        fkey: String::new(),
+        chat_server: endpoints.chat_server.clone(),
+        cookie_store
+    })
+}
+
+/// Logs in as `user_id`, or reuses the session saved in `state_dir` if it's recent enough.
+pub async fn log_in(user_id: &str, user_config: &UserConfig, endpoints: &EndpointsConfig, state_dir: &Path) -> Result<User> {
+    let credentials = retrieve_credentials(user_id, state_dir).await.ok();
 
     let cookie_store = if credentials.is_some() {
-        Arc::new(reqwest_cookie_store::CookieStoreMutex::new(reqwest_cookie_store::CookieStore::load_json(std::io::BufReader::new(std::fs::File::open(format!("tmp/{}-cookies.json", user_id))?))?))
+        Arc::new(reqwest_cookie_store::CookieStoreMutex::new(reqwest_cookie_store::CookieStore::load_json(std::io::BufReader::new(std::fs::File::open(state_dir.join(format!("{}-cookies.json", user_id)))?))?))
     } else {
         Arc::new(reqwest_cookie_store::CookieStoreMutex::new(reqwest_cookie_store::CookieStore::default()))
     };
 
-    let client = reqwest::ClientBuilder::new().user_agent("Mozilla/5.0 (compatible; NPSP/2.0; +https://chat.stackexchange.com/rooms/240/the-nineteenth-byte)").cookie_store(true).cookie_provider(Arc::clone(&cookie_store)).gzip(true).build()?;
+    let client = reqwest::ClientBuilder::new().user_agent(USER_AGENT).cookie_store(true).cookie_provider(Arc::clone(&cookie_store)).gzip(true).build()?;
 
     let fkey;
 
     if let Some(credentials) = credentials {
         fkey = credentials.fkey;
         
-        println!("login: found from {} mins ago", (time() - credentials.time) / 60000);
+        info!(user = user_id, age_mins = (time() - credentials.time) / 60000, "reusing saved credentials");
     } else {
-        let login = try_login(&client, &user_config.email, &user_config.password).await?;
+        let login = try_login(&client, endpoints, &user_config.email, &user_config.password).await?;
         
-        tokio::fs::create_dir_all("tmp").await?;
-        tokio::fs::write(format!("tmp/{}-credentials.json", user_id), serde_json::to_string(&login)?).await?;
+        tokio::fs::create_dir_all(state_dir).await?;
+        tokio::fs::write(state_dir.join(format!("{}-credentials.json", user_id)), serde_json::to_string(&login)?).await?;
         
         fkey = login.fkey;
         
-        cookie_store.lock().unwrap().save_json(&mut std::io::BufWriter::new(std::fs::File::create(format!("tmp/{}-cookies.json", user_id))?))?;
-        
-        println!("login: successful");
+        info!(user = user_id, "logged in");
     }
     
-    Ok(User {
-        client: client,
-        fkey: fkey
-    })
+    let user = User {
+        id: user_id.to_owned(),
+        client,
+        fkey,
+        chat_server: endpoints.chat_server.clone(),
+        cookie_store
+    };
+    
+    user.save_cookies(state_dir)?;
+    
+    Ok(user)
 }
\ No newline at end of file
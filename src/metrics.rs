@@ -0,0 +1,157 @@
+use std::error::Error;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use tracing::{info, instrument};
+
+use crate::{config::MetricsConfig, http::{self, Request, Response}, shutdown::Shutdown};
+
+// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram"
+        }
+    }
+}
+
+// Every metric, so each gets a `# HELP` and `# TYPE` line even before it has any samples
+const METRICS: &[(&str, Kind, &str)] = &[
+    ("npsp_posts_total", Kind::Counter, "Links and other messages sent to chat, per route and kind"),
+    ("npsp_socket_events_total", Kind::Counter, "Events received from qa.sockets, per action, after removing duplicates between replicas"),
+    ("npsp_reconnects_total", Kind::Counter, "Websocket connections closed, per socket and reason"),
+    ("npsp_chat_cooldowns_total", Kind::Counter, "Times a chat message had to wait for a cooldown, per room"),
+    ("npsp_chat_cooldown_seconds_total", Kind::Counter, "Time spent waiting for chat cooldowns, per room"),
+    ("npsp_api_quota_remaining", Kind::Gauge, "API quota left today, as of the last response"),
+    ("npsp_wait_for_api_seconds", Kind::Histogram, "How long posts from the socket took to show up on the API, per outcome"),
+    ("npsp_post_to_chat_seconds", Kind::Histogram, "Time from a post's creation to its link being sent to chat, per room"),
+    ("npsp_known_ids", Kind::Gauge, "Post IDs known to have been linked, per room and site"),
+    ("npsp_queue_depth", Kind::Gauge, "Messages waiting to be posted, per room")
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+struct Histogram {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64
+}
+
+#[derive(Default)]
+struct Registry {
+    values: BTreeMap<(&'static str, Labels), f64>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>
+}
+
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+fn with_registry<T>(f: impl FnOnce(&mut Registry) -> T) -> T {
+    f(REGISTRY.lock().unwrap().get_or_insert_with(Registry::default))
+}
+
+fn labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels.iter().map(|(name, value)| (*name, value.to_string())).collect()
+}
+
+/// Adds `value` to a counter.
+pub fn add(name: &'static str, label_values: &[(&'static str, &str)], value: f64) {
+    with_registry(|registry| *registry.values.entry((name, labels(label_values))).or_default() += value);
+}
+
+pub fn inc(name: &'static str, label_values: &[(&'static str, &str)]) {
+    add(name, label_values, 1.0);
+}
+
+pub fn set(name: &'static str, label_values: &[(&'static str, &str)], value: f64) {
+    with_registry(|registry| registry.values.insert((name, labels(label_values)), value));
+}
+
+/// Records a sample in a histogram, in seconds.
+pub fn observe(name: &'static str, label_values: &[(&'static str, &str)], seconds: f64) {
+    with_registry(|registry| {
+        let histogram = registry.histograms.entry((name, labels(label_values))).or_insert_with(|| Histogram {
+            counts: vec![0; BUCKETS.len()],
+            sum: 0.0,
+            count: 0
+        });
+
+        for (bucket, count) in BUCKETS.iter().zip(histogram.counts.iter_mut()) {
+            if seconds <= *bucket {
+                *count += 1;
+            }
+        }
+
+        histogram.sum += seconds;
+        histogram.count += 1;
+    });
+}
+
+fn render_labels(labels: &Labels, extra: Option<(&str, String)>) -> String {
+    let rendered = labels.iter()
+        .map(|(name, value)| (*name, value.clone()))
+        .chain(extra)
+        .map(|(name, value)| format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect::<Vec<String>>();
+
+    if rendered.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", rendered.join(","))
+    }
+}
+
+/// Everything recorded so far, in the Prometheus text format.
+pub fn render() -> String {
+    with_registry(|registry| {
+        let mut out = String::new();
+
+        for (name, kind, help) in METRICS {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind.name());
+
+            if *kind == Kind::Histogram {
+                for ((_, labels), histogram) in registry.histograms.range((*name, Vec::new())..).take_while(|((metric, _), _)| metric == name) {
+                    for (bucket, count) in BUCKETS.iter().zip(&histogram.counts) {
+                        let _ = writeln!(out, "{}_bucket{} {}", name, render_labels(labels, Some(("le", bucket.to_string()))), count);
+                    }
+
+                    let _ = writeln!(out, "{}_bucket{} {}", name, render_labels(labels, Some(("le", "+Inf".to_owned()))), histogram.count);
+                    let _ = writeln!(out, "{}_sum{} {}", name, render_labels(labels, None), histogram.sum);
+                    let _ = writeln!(out, "{}_count{} {}", name, render_labels(labels, None), histogram.count);
+                }
+            } else {
+                for ((_, labels), value) in registry.values.range((*name, Vec::new())..).take_while(|((metric, _), _)| metric == name) {
+                    let _ = writeln!(out, "{}{} {}", name, render_labels(labels, None), value);
+                }
+            }
+        }
+
+        out
+    })
+}
+
+/// Serves `GET /metrics` on the configured address.
+#[instrument(name = "metrics", skip_all)]
+pub async fn serve(config: &MetricsConfig, shutdown: Shutdown) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let listener = http::bind(&config.listen).await?;
+
+    info!(listen = %config.listen, "serving /metrics");
+
+    http::serve(listener, &shutdown, |request: Request| async move {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => Response::text(200, render()),
+            _ => Response::text(404, "not found\n")
+        }
+    }).await
+}
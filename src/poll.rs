@@ -0,0 +1,88 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn, error, instrument};
+
+use crate::{config::WatchSource, retry::{self, Severity}, watch::{Watcher, FeedPoll, Source}};
+
+/// Picks the next polling interval: faster while posts keep turning up, slower while they don't, and as slow as
+/// allowed when the API is low on quota. An API `backoff` is always honoured.
+fn next_interval(interval: Duration, poll: &FeedPoll, min: Duration, max: Duration, low_quota: u64) -> Duration {
+    let interval = if poll.quota_remaining.is_some_and(|quota| quota < low_quota) {
+        max
+    } else if poll.new_posts > 0 {
+        (interval / 2).max(min)
+    } else {
+        interval.mul_f32(1.5).min(max)
+    };
+
+    interval.max(Duration::from_secs(poll.backoff.unwrap_or(0)))
+}
+
+/// Polls one watch socket's feed through the API, either always or, for `auto` watch sockets, only while that feed is
+/// silent on qa.sockets, going back to waiting on the socket once it's heard from again.
+#[instrument(name = "poll", skip_all, fields(watch_socket = %watch_socket_id))]
+pub async fn poll(watcher: Arc<Watcher>, watch_socket_id: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = watcher.config();
+    let shutdown = watcher.shutdown();
+    let polling = config.get_polling();
+    let source = config.get_watch_sockets()[&watch_socket_id].source;
+
+    let (min, max) = (Duration::from_millis(polling.min_interval_ms), Duration::from_millis(polling.max_interval_ms));
+    let silence = Duration::from_millis(polling.silence_ms);
+
+    let mut interval = min;
+    let mut polling_now = false;
+
+    loop {
+        if source == WatchSource::Auto && !watcher.socket_silent(&watch_socket_id, silence).await {
+            if polling_now {
+                info!("socket is back, stopping");
+
+                polling_now = false;
+            }
+
+            if shutdown.until(tokio::time::sleep(min)).await.is_none() {
+                return Ok(());
+            }
+
+            continue;
+        }
+
+        if !polling_now {
+            info!(interval_s = min.as_secs(), "{}, polling", if source == WatchSource::Poll { "configured to poll" } else { "socket is silent" });
+
+            polling_now = true;
+            interval = min;
+        }
+
+        match watcher.catch_up(&watch_socket_id, Source::Poll).await {
+            Ok(Some(poll)) => {
+                interval = next_interval(interval, &poll, min, max, polling.low_quota);
+
+                if poll.new_posts > 0 || poll.backoff.is_some() {
+                    info!(new_posts = poll.new_posts, quota = poll.quota_remaining, next_s = interval.as_secs(), "polled");
+                }
+            }
+            Ok(None) => {
+                warn!("can't be polled");
+
+                return Ok(());
+            }
+            Err(err) if retry::classify(err.as_ref()) == Severity::Fatal => {
+                error!(%err, "giving up");
+
+                return Err(err);
+            }
+            Err(err) => {
+                interval = max;
+
+                warn!(%err, next_s = interval.as_secs(), "poll failed");
+            }
+        }
+
+        if shutdown.until(tokio::time::sleep(interval)).await.is_none() {
+            return Ok(());
+        }
+    }
+}
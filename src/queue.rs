@@ -0,0 +1,585 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::collections::{HashMap, BTreeMap, VecDeque};
+use std::time::Duration;
+use std::path::{Path, PathBuf};
+use tokio::sync::{Mutex, Notify};
+use serde::{Serialize, Deserialize};
+use tracing::{info, warn, debug, instrument};
+
+use crate::{time, TMP_FILE_REVISION, metrics, shutdown::Shutdown, login::User, chat::{self, PostOutcome}};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+const QUEUE_FILE: &str = "queue.json";
+
+// Failed sends (anything other than a cooldown) are retried this many times before the message is dropped
+const MAX_FAILED_ATTEMPTS: u32 = 5;
+
+// Cooldowns are expected, but a message that keeps getting throttled is eventually given up on as well
+const MAX_COOLDOWNS: u32 = 20;
+
+const MAX_MESSAGE_LENGTH: usize = 500;
+
+/// What a queued link points at, so several of them can be announced together.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LinkKind {
+    Question,
+    Answer
+}
+
+impl LinkKind {
+    pub fn noun(&self, count: usize) -> &'static str {
+        match (self, count) {
+            (LinkKind::Question, 1) => "question",
+            (LinkKind::Question, _) => "questions",
+            (LinkKind::Answer, 1) => "answer",
+            (LinkKind::Answer, _) => "answers"
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct QueuedMessage {
+    seq: u64,
+    created: u128,
+    room_id: u64,
+    user_id: String,
+    // The route that queued it, if any, for metrics
+    #[serde(default)]
+    route: Option<String>,
+    text: String,
+    // Set for links that may be merged with others of the same kind until `hold_until`
+    #[serde(default)]
+    link: Option<LinkKind>,
+    #[serde(default)]
+    hold_until: Option<u128>,
+    // When the linked post was created, if known
+    #[serde(default)]
+    post_created: Option<u128>,
+    // Logged rather than posted
+    #[serde(default)]
+    dry_run: bool
+}
+
+enum Batch {
+    Empty,
+    Wait(u128),
+    Ready(Vec<QueuedMessage>)
+}
+
+/// What goes out next from a room's queue. Held links go out straight away once `flushing`, rather than waiting out
+/// their window.
+fn next_batch(room: Option<&VecDeque<QueuedMessage>>, flushing: bool) -> Batch {
+    let front = match room.and_then(|room| room.front()) {
+        Some(front) => front,
+        None => return Batch::Empty
+    };
+
+    let (kind, hold_until) = match (front.link, front.hold_until) {
+        (Some(kind), Some(hold_until)) => (kind, hold_until),
+        _ => return Batch::Ready(vec![front.clone()])
+    };
+
+    let now = time();
+
+    if hold_until > now && !flushing {
+        return Batch::Wait(hold_until - now);
+    }
+
+    Batch::Ready(room.unwrap().iter().take_while(|message| {
+        message.user_id == front.user_id && message.link == Some(kind) && message.hold_until.is_some() && message.dry_run == front.dry_run
+    }).cloned().collect())
+}
+
+/// Merges links into as few messages as fit in the chat length limit, e.g. "3 new questions: …", each with how many
+/// links it holds.
+///
+/// Links that end up alone are posted bare, so they still get a onebox.
+fn coalesce(kind: LinkKind, urls: &[&str]) -> Vec<(usize, String)> {
+    let mut messages = Vec::new();
+    let mut chunk: Vec<&str> = Vec::new();
+
+    let render = |chunk: &[&str]| if chunk.len() == 1 {
+        chunk[0].to_owned()
+    } else {
+        format!("{} new {}: {}", chunk.len(), kind.noun(chunk.len()), chunk.join(" "))
+    };
+
+    for url in urls {
+        chunk.push(url);
+
+        if chunk.len() > 1 && render(&chunk).len() > MAX_MESSAGE_LENGTH {
+            chunk.pop();
+
+            messages.push((chunk.len(), render(&chunk)));
+
+            chunk = vec![url];
+        }
+    }
+
+    if !chunk.is_empty() {
+        messages.push((chunk.len(), render(&chunk)));
+    }
+
+    messages
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedQueue {
+    revision: String,
+    next_seq: u64,
+    messages: Vec<QueuedMessage>
+}
+
+struct QueueState {
+    next_seq: u64,
+    rooms: HashMap<u64, VecDeque<QueuedMessage>>
+}
+
+impl QueueState {
+    /// Rebuilds the queue from disk, leaving out messages for users that aren't logged in. With `dry_run`, everything
+    /// restored is only logged, even if it was queued by a live run.
+    fn restore(saved: Option<SavedQueue>, users: &HashMap<String, Arc<User>>, dry_run: bool) -> QueueState {
+        let mut state = QueueState {
+            next_seq: 0,
+            rooms: HashMap::new()
+        };
+
+        if let Some(mut saved) = saved {
+            saved.messages.sort_by_key(|message| message.seq);
+
+            state.next_seq = saved.next_seq;
+
+            for mut message in saved.messages {
+                if !users.contains_key(&message.user_id) {
+                    warn!(room = message.room_id, seq = message.seq, user = %message.user_id, "dropping message for unknown user");
+
+                    continue;
+                }
+
+                message.dry_run |= dry_run;
+
+                state.next_seq = state.next_seq.max(message.seq + 1);
+                state.rooms.entry(message.room_id).or_default().push_back(message);
+            }
+        }
+
+        state
+    }
+}
+
+/// Outbound chat messages, serialised per room so concurrent posters don't race the chat throttle.
+///
+/// Pending messages are written to `queue.json` in the state directory whenever the queue changes, and picked back up
+/// by `MessageQueue::load` on the next start.
+pub struct MessageQueue {
+    users: HashMap<String, Arc<User>>,
+    state_dir: PathBuf,
+    state: Mutex<QueueState>,
+    workers: Mutex<HashMap<u64, Arc<Notify>>>,
+    // Held links are flushed once it's requested
+    shutdown: Shutdown
+}
+
+impl MessageQueue {
+    /// Picks up whatever was still queued when the bot last stopped. With `dry_run`, none of it is posted.
+    pub async fn load(users: HashMap<String, Arc<User>>, state_dir: &Path, dry_run: bool, shutdown: Shutdown) -> Result<Arc<MessageQueue>> {
+        let saved = match tokio::fs::read_to_string(state_dir.join(QUEUE_FILE)).await {
+            Ok(json) => serde_json::from_str::<SavedQueue>(&json).ok().filter(|saved| saved.revision == TMP_FILE_REVISION),
+            Err(_) => None
+        };
+
+        let state = QueueState::restore(saved, &users, dry_run);
+
+        let rooms = state.rooms.iter().map(|(room_id, messages)| (*room_id, messages.len())).collect::<Vec<(u64, usize)>>();
+
+        let queue = Arc::new(MessageQueue {
+            users,
+            state_dir: state_dir.to_owned(),
+            state: Mutex::new(state),
+            workers: Mutex::new(HashMap::new()),
+            shutdown
+        });
+
+        for (room_id, depth) in rooms {
+            info!(room = room_id, depth, "restored queue");
+
+            metrics::set("npsp_queue_depth", &[("room", &room_id.to_string())], depth as f64);
+
+            queue.wake(room_id).await;
+        }
+
+        Ok(queue)
+    }
+
+    /// Queues `text` to be posted to `room_id` as `user_id`, after everything already queued for that room. With
+    /// `dry_run`, it's only logged when its turn comes. `route` is only used to count what was sent.
+    pub async fn push(self: &Arc<Self>, room_id: u64, user_id: &str, route: Option<&str>, text: String, dry_run: bool) {
+        self.enqueue(room_id, user_id, route, text, None, None, None, dry_run).await;
+    }
+
+    /// Queues `url` to be posted to `room_id` as `user_id`, after everything already queued for that room.
+    ///
+    /// If `window` is set, the link is held for that long so that other links of the same kind queued in the meantime
+    /// go out in a single message. `post_created` is only used to measure how long posts take to reach chat.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn push_link(self: &Arc<Self>, room_id: u64, user_id: &str, route: &str, kind: LinkKind, url: String, window: Option<Duration>, post_created: Option<u128>, dry_run: bool) {
+        let hold_until = window.map(|window| time() + window.as_millis());
+
+        self.enqueue(room_id, user_id, Some(route), url, Some(kind), hold_until, post_created, dry_run).await;
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn enqueue(self: &Arc<Self>, room_id: u64, user_id: &str, route: Option<&str>, text: String, link: Option<LinkKind>, hold_until: Option<u128>, post_created: Option<u128>, dry_run: bool) {
+        {
+            let mut state = self.state.lock().await;
+
+            let message = QueuedMessage {
+                seq: state.next_seq,
+                created: time(),
+                room_id,
+                user_id: user_id.to_owned(),
+                route: route.map(str::to_owned),
+                text,
+                link,
+                hold_until,
+                post_created,
+                dry_run
+            };
+
+            let seq = state.next_seq;
+
+            state.next_seq += 1;
+
+            let room = state.rooms.entry(room_id).or_default();
+
+            room.push_back(message);
+
+            let depth = room.len();
+
+            debug!(room = room_id, seq, depth, "queued");
+
+            metrics::set("npsp_queue_depth", &[("room", &room_id.to_string())], depth as f64);
+
+            self.save(&state).await;
+        }
+
+        self.wake(room_id).await;
+    }
+
+    pub async fn depth(&self, room_id: u64) -> usize {
+        self.state.lock().await.rooms.get(&room_id).map_or(0, |room| room.len())
+    }
+
+    /// How many messages are waiting in each room that has any.
+    pub async fn depths(&self) -> BTreeMap<u64, usize> {
+        self.state.lock().await.rooms.iter().map(|(room_id, room)| (*room_id, room.len())).collect()
+    }
+
+    /// Waits until every room's queue is empty. Whatever's still queued when the caller gives up is already on disk.
+    pub async fn drain(&self) {
+        loop {
+            let pending = self.state.lock().await.rooms.values().map(|room| room.len()).sum::<usize>();
+
+            if pending == 0 {
+                return;
+            }
+
+            debug!(pending, "waiting for the queue to drain");
+
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    async fn wake(self: &Arc<Self>, room_id: u64) {
+        let mut workers = self.workers.lock().await;
+
+        let notify = workers.entry(room_id).or_insert_with(|| {
+            let notify = Arc::new(Notify::new());
+
+            tokio::spawn(Arc::clone(self).work(room_id, Arc::clone(&notify)));
+
+            notify
+        });
+
+        notify.notify_one();
+    }
+
+    #[instrument(name = "queue", skip_all, fields(room = room_id))]
+    async fn work(self: Arc<Self>, room_id: u64, notify: Arc<Notify>) {
+        loop {
+            let batch = next_batch(self.state.lock().await.rooms.get(&room_id), self.shutdown.is_requested());
+
+            let messages = match batch {
+                Batch::Empty => {
+                    notify.notified().await;
+
+                    continue;
+                }
+                Batch::Wait(millis) => {
+                    self.shutdown.until(tokio::time::sleep(Duration::from_millis(millis as u64))).await;
+
+                    continue;
+                }
+                Batch::Ready(messages) => messages
+            };
+
+            let first = &messages[0];
+
+            let mut sent = true;
+
+            if messages.len() == 1 {
+                sent = self.send(first, &first.text).await;
+
+                if sent {
+                    Self::count_sent(&messages);
+                }
+            } else {
+                let urls = messages.iter().map(|message| message.text.as_str()).collect::<Vec<&str>>();
+                let texts = coalesce(first.link.unwrap(), &urls);
+
+                info!(first_seq = first.seq, last_seq = messages[messages.len() - 1].seq, messages = texts.len(), "coalesced links");
+
+                let mut offset = 0;
+
+                for (count, text) in texts {
+                    if self.send(first, &text).await {
+                        Self::count_sent(&messages[offset..offset + count]);
+                    } else {
+                        sent = false;
+                    }
+
+                    offset += count;
+                }
+            }
+
+            if sent {
+                let now = time();
+
+                for post_created in messages.iter().filter_map(|message| message.post_created) {
+                    metrics::observe("npsp_post_to_chat_seconds", &[("room", &room_id.to_string())], now.saturating_sub(post_created) as f64 / 1000.0);
+                }
+            }
+
+            {
+                let mut state = self.state.lock().await;
+
+                if let Some(room) = state.rooms.get_mut(&room_id) {
+                    room.retain(|queued| !messages.iter().any(|message| message.seq == queued.seq));
+
+                    if room.is_empty() {
+                        state.rooms.remove(&room_id);
+                    }
+                }
+
+                self.save(&state).await;
+            }
+
+            let depth = self.depth(room_id).await;
+
+            debug!(seq = first.seq, depth, "done");
+
+            metrics::set("npsp_queue_depth", &[("room", &room_id.to_string())], depth as f64);
+        }
+    }
+
+    // Counts messages that made it to chat, by the route that queued them
+    fn count_sent(messages: &[QueuedMessage]) {
+        for message in messages.iter().filter(|message| !message.dry_run) {
+            if let Some(route) = &message.route {
+                metrics::inc("npsp_posts_total", &[("route", route), ("kind", message.link.map_or("message", |kind| kind.noun(1)))]);
+            }
+        }
+    }
+
+    /// Returns whether the message was sent, rather than given up on.
+    async fn send(&self, message: &QueuedMessage, text: &str) -> bool {
+        if message.dry_run {
+            info!(seq = message.seq, user = %message.user_id, %text, "dry run, not posting");
+
+            return true;
+        }
+
+        let user = &self.users[&message.user_id];
+
+        let mut failures = 0;
+        let mut cooldowns = 0;
+
+        loop {
+            match chat::post(message.room_id, text, user).await {
+                PostOutcome::Sent => {
+                    info!(seq = message.seq, latency_ms = (time() - message.created) as u64, "sent");
+
+                    return true;
+                }
+                PostOutcome::Cooldown(cooldown) => {
+                    cooldowns += 1;
+
+                    if cooldowns > MAX_COOLDOWNS {
+                        warn!(seq = message.seq, cooldowns, "throttled too often, dropping");
+
+                        return false;
+                    }
+
+                    info!(seq = message.seq, cooldown_s = cooldown, "cooldown");
+
+                    metrics::inc("npsp_chat_cooldowns_total", &[("room", &message.room_id.to_string())]);
+                    metrics::add("npsp_chat_cooldown_seconds_total", &[("room", &message.room_id.to_string())], cooldown as f64 + 2.0);
+
+                    tokio::time::sleep(Duration::from_millis(cooldown * 1000 + 2000)).await;
+                }
+                PostOutcome::Failed(err) => {
+                    failures += 1;
+
+                    if failures >= MAX_FAILED_ATTEMPTS {
+                        warn!(seq = message.seq, %err, "failed too often, dropping");
+
+                        return false;
+                    }
+
+                    warn!(seq = message.seq, %err, "failed, retrying");
+
+                    tokio::time::sleep(Duration::from_millis(1000 << failures)).await;
+                }
+            }
+        }
+    }
+
+    async fn save(&self, state: &QueueState) {
+        let mut messages = state.rooms.values().flatten().cloned().collect::<Vec<QueuedMessage>>();
+
+        messages.sort_by_key(|message| message.seq);
+
+        let saved = SavedQueue {
+            revision: TMP_FILE_REVISION.to_string(),
+            next_seq: state.next_seq,
+            messages
+        };
+
+        let result: Result<()> = async {
+            tokio::fs::create_dir_all(&self.state_dir).await?;
+            tokio::fs::write(self.state_dir.join(QUEUE_FILE), serde_json::to_string(&saved)?).await?;
+
+            Ok(())
+        }.await;
+
+        if let Err(err) = result {
+            warn!(%err, "failed to save queue");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{login, config::EndpointsConfig};
+
+    fn link(seq: u64, kind: LinkKind, hold_until: Option<u128>) -> QueuedMessage {
+        QueuedMessage {
+            seq,
+            created: 0,
+            room_id: 240,
+            user_id: "np".to_owned(),
+            route: Some("golf".to_owned()),
+            text: format!("https://codegolf.stackexchange.com/q/{}", seq),
+            link: Some(kind),
+            hold_until,
+            post_created: None,
+            dry_run: false
+        }
+    }
+
+    fn seqs(batch: Batch) -> Vec<u64> {
+        match batch {
+            Batch::Ready(messages) => messages.iter().map(|message| message.seq).collect(),
+            _ => panic!("batch isn't ready")
+        }
+    }
+
+    #[test]
+    fn restore_flags_every_message_dry_run_in_a_dry_run() {
+        let users = HashMap::from([("np".to_owned(), Arc::new(login::offline("np", &EndpointsConfig::default()).unwrap()))]);
+
+        let saved = || SavedQueue {
+            revision: TMP_FILE_REVISION.to_owned(),
+            next_seq: 2,
+            messages: vec![link(1, LinkKind::Answer, None), link(0, LinkKind::Question, None), QueuedMessage {
+                user_id: "gone".to_owned(),
+                ..link(2, LinkKind::Question, None)
+            }]
+        };
+
+        let state = QueueState::restore(Some(saved()), &users, true);
+
+        assert_eq!(state.next_seq, 2);
+        assert!(state.rooms[&240].iter().map(|message| (message.seq, message.dry_run)).eq([(0, true), (1, true)]));
+
+        let state = QueueState::restore(Some(saved()), &users, false);
+
+        assert!(state.rooms[&240].iter().all(|message| !message.dry_run));
+    }
+
+    #[test]
+    fn coalesce_fills_messages_up_to_the_limit() {
+        // "2 new questions: " plus two URLs and the space between them is exactly the limit
+        let prefix = "2 new questions: ".len();
+        let first = "a".repeat((MAX_MESSAGE_LENGTH - prefix - 1) / 2);
+        let second = "b".repeat(MAX_MESSAGE_LENGTH - prefix - 1 - first.len());
+
+        let messages = coalesce(LinkKind::Question, &[&first, &second]);
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, 2);
+        assert_eq!(messages[0].1.len(), MAX_MESSAGE_LENGTH);
+
+        let longer = format!("{}b", second);
+
+        assert_eq!(coalesce(LinkKind::Question, &[&first, &longer]), [(1, first), (1, longer)]);
+    }
+
+    #[test]
+    fn coalesce_posts_an_overlong_url_on_its_own() {
+        let long = format!("https://codegolf.stackexchange.com/q/1/{}", "a".repeat(MAX_MESSAGE_LENGTH));
+
+        assert_eq!(coalesce(LinkKind::Answer, &[&long]), [(1, long.clone())]);
+        assert_eq!(coalesce(LinkKind::Answer, &["x", &long, "y", "z"]), [(1, "x".to_owned()), (1, long), (2, "2 new answers: y z".to_owned())]);
+    }
+
+    #[test]
+    fn next_batch_stops_at_a_different_kind() {
+        let room = VecDeque::from([
+            link(0, LinkKind::Question, Some(0)),
+            link(1, LinkKind::Question, Some(0)),
+            link(2, LinkKind::Answer, Some(0)),
+            link(3, LinkKind::Question, Some(0))
+        ]);
+
+        assert_eq!(seqs(next_batch(Some(&room), false)), [0, 1]);
+
+        // Links that aren't held go out on their own
+        let room = VecDeque::from([link(0, LinkKind::Question, None), link(1, LinkKind::Question, Some(0))]);
+
+        assert_eq!(seqs(next_batch(Some(&room), false)), [0]);
+    }
+
+    #[test]
+    fn next_batch_waits_out_the_hold_window() {
+        assert!(matches!(next_batch(None, false), Batch::Empty));
+
+        let now = time();
+
+        let room = VecDeque::from([link(0, LinkKind::Question, Some(now + 60000))]);
+
+        assert!(matches!(next_batch(Some(&room), false), Batch::Wait(millis) if millis > 0 && millis <= 60000));
+
+        // Except when shutting down
+        assert_eq!(seqs(next_batch(Some(&room), true)), [0]);
+
+        // Once the front's window is over, links queued after it still held go out with it
+        let room = VecDeque::from([link(0, LinkKind::Question, Some(now - 1)), link(1, LinkKind::Question, Some(now + 60000))]);
+
+        assert_eq!(seqs(next_batch(Some(&room), false)), [0, 1]);
+    }
+}
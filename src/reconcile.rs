@@ -0,0 +1,52 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn, instrument};
+
+use crate::{time, retry::{self, Severity}, watch::Watcher};
+
+// Posts younger than this are left alone, so the socket gets a chance to deliver them first
+const GRACE: u128 = 120000;
+
+/// Periodically checks a route's feed for posts that every other source missed, and logs how many posts each source
+/// has delivered first.
+#[instrument(name = "reconcile", skip_all, fields(route = %route_id))]
+pub async fn reconcile(watcher: Arc<Watcher>, route_id: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = watcher.config();
+    let shutdown = watcher.shutdown();
+    let reconcile = match config.get_reconcile() {
+        Some(reconcile) => reconcile,
+        None => return Ok(())
+    };
+
+    let interval = Duration::from_millis(reconcile.interval_ms);
+
+    let mut ticker = tokio::time::interval(interval);
+
+    ticker.tick().await;
+
+    while shutdown.until(ticker.tick()).await.is_some() {
+        let now = time();
+
+        // Each run overlaps the last, in case the API was slow to show a post
+        match watcher.reconcile(&route_id, now - GRACE - 2 * interval.as_millis(), now - GRACE, reconcile.report_only).await {
+            Ok(Some(missed)) if !missed.is_empty() => {
+                warn!(missed = missed.len(), post_ids = ?missed, report_only = reconcile.report_only, "found posts every other source missed");
+            }
+            Ok(Some(_)) => (),
+            Ok(None) => {
+                warn!("can't be reconciled");
+
+                return Ok(());
+            }
+            Err(err) if retry::classify(err.as_ref()) == Severity::Fatal => return Err(err),
+            Err(err) => warn!(%err, "reconciliation failed")
+        }
+
+        if let Some(deliveries) = watcher.first_deliveries().await.get(&route_id) {
+            info!(first_deliveries = ?deliveries, "first deliveries by source");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use std::{error::Error, fmt};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn, debug, instrument};
+
+use crate::{time, capture::{Entry, Event}, chat::ChatRoom, queue::MessageQueue, watch::Watcher};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+// Left to play out after the last event, so API waits, holds and retries started near the end still finish
+const SETTLE: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug)]
+struct BadCapture {
+    line: usize,
+    err: serde_json::Error
+}
+
+impl Error for BadCapture {}
+
+impl fmt::Display for BadCapture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad capture line {}: {}", self.line, self.err)
+    }
+}
+
+#[derive(Debug)]
+struct NotInCapture {
+    request: String
+}
+
+impl Error for NotInCapture {}
+
+impl fmt::Display for NotInCapture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no response to {} in the capture", self.request)
+    }
+}
+
+struct Replay {
+    // The capture's first timestamp, and the (paused) runtime's time when replay started
+    start: (u128, Instant),
+    // Recorded responses per request, handed out in order; the last is repeated once the others are used up
+    responses: Mutex<HashMap<String, VecDeque<(u16, String)>>>
+}
+
+static REPLAY: OnceLock<Replay> = OnceLock::new();
+
+/// The socket events of a capture, in order. Its API responses are served to `Api` instead.
+pub struct Capture {
+    events: Vec<(u128, Event)>
+}
+
+/// Reads the capture at `path` and starts the virtual clock at its first timestamp. From then on, `time()` follows the
+/// runtime's clock, which is expected to be paused so that it jumps straight to whatever's due next.
+pub fn load(path: &str) -> Result<Capture> {
+    let mut events = Vec::new();
+    let mut responses: HashMap<String, VecDeque<(u16, String)>> = HashMap::new();
+
+    for (index, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry = serde_json::from_str::<Entry>(line).map_err(|err| BadCapture {
+            line: index + 1,
+            err
+        })?;
+
+        match entry.event {
+            Event::Api { request, status, body } => responses.entry(request).or_default().push_back((status, body)),
+            event => events.push((entry.at as u128, event))
+        }
+    }
+
+    events.sort_by_key(|(at, _)| *at);
+
+    let first = events.first().map_or_else(time, |(at, _)| *at);
+
+    let _ = REPLAY.set(Replay {
+        start: (first, Instant::now()),
+        responses: Mutex::new(responses)
+    });
+
+    Ok(Capture { events })
+}
+
+pub fn is_replaying() -> bool {
+    REPLAY.get().is_some()
+}
+
+/// The virtual time, in ms since the epoch, while replaying.
+pub fn virtual_time() -> Option<u128> {
+    REPLAY.get().map(|replay| replay.start.0 + replay.start.1.elapsed().as_millis())
+}
+
+/// The recorded response to `request`, as `(status, body)`, or `None` when not replaying.
+pub fn response(request: &str) -> Option<Result<(u16, String)>> {
+    let replay = REPLAY.get()?;
+
+    let mut responses = replay.responses.lock().unwrap();
+
+    let response = match responses.get_mut(request) {
+        Some(recorded) if recorded.len() > 1 => recorded.pop_front(),
+        Some(recorded) => recorded.front().cloned(),
+        None => None
+    };
+
+    Some(response.ok_or_else(|| Box::new(NotInCapture { request: request.to_owned() }) as Box<dyn Error + Send + Sync>))
+}
+
+/// Feeds the capture's socket events through the watcher and chat rooms at the times they were recorded, then waits for
+/// everything they set off to settle and the queue to drain, and requests shutdown.
+///
+/// `rooms` is keyed by room and user, as chat frames were recorded.
+#[instrument(name = "replay", skip_all)]
+pub async fn run(capture: Capture, watcher: Arc<Watcher>, rooms: HashMap<(u64, String), Arc<ChatRoom>>, queue: Arc<MessageQueue>) {
+    let shutdown = watcher.shutdown();
+    let count = capture.events.len();
+
+    info!(events = count, "replaying");
+
+    for (at, event) in capture.events {
+        if shutdown.until(tokio::time::sleep(Duration::from_millis(at.saturating_sub(time()) as u64))).await.is_none() {
+            return;
+        }
+
+        match event {
+            Event::WatchConnecting => {
+                if let Err(err) = watcher.post_from_api().await {
+                    warn!(%err, "catch-up failed");
+                }
+            }
+            Event::WatchFrame { replica, frame } => {
+                // Heartbeats only need answering on a live socket
+                watcher.handle_frame(replica, frame).await;
+            }
+            Event::ChatFrame { room_id, user, frame } => match rooms.get(&(room_id, user)) {
+                Some(room) => room.handle_frame(&frame).await,
+                None => debug!(room = room_id, "no chat connection like the recorded one, skipping frame")
+            },
+            Event::Api { .. } => ()
+        }
+    }
+
+    info!(events = count, settle_s = SETTLE.as_secs(), "replayed every event, letting things settle");
+
+    if shutdown.until(async {
+        tokio::time::sleep(SETTLE).await;
+        queue.drain().await;
+    }).await.is_some() {
+        info!("replay finished");
+
+        shutdown.request();
+    }
+}
@@ -0,0 +1,87 @@
+use std::error::Error;
+use std::time::Duration;
+use rand::Rng;
+use tokio_tungstenite::tungstenite;
+
+use crate::api::ApiError;
+
+/// Whether a failed connection is worth trying again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Network trouble, server errors, dropped sockets and garbled responses; retried with backoff
+    Transient,
+    /// Retrying won't help (rejected credentials, missing rooms, malformed URLs); only the affected connection stops
+    Fatal
+}
+
+fn status_severity(status: u16) -> Severity {
+    match status {
+        400 | 401 | 403 | 404 => Severity::Fatal,
+        _ => Severity::Transient
+    }
+}
+
+pub fn classify(err: &(dyn Error + Send + Sync + 'static)) -> Severity {
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        if err.is_builder() {
+            return Severity::Fatal;
+        }
+
+        return err.status().map_or(Severity::Transient, |status| status_severity(status.as_u16()));
+    }
+
+    if let Some(err) = err.downcast_ref::<ApiError>() {
+        // internal_error, throttle_violation and temporarily_unavailable; the rest are mistakes in the request
+        return match err.error_id {
+            Some(500 | 502 | 503) => Severity::Transient,
+            Some(_) => Severity::Fatal,
+            None => status_severity(err.status)
+        };
+    }
+
+    if let Some(err) = err.downcast_ref::<tungstenite::Error>() {
+        return match err {
+            tungstenite::Error::Url(_) => Severity::Fatal,
+            tungstenite::Error::Http(response) => status_severity(response.status().as_u16()),
+            _ => Severity::Transient
+        };
+    }
+
+    if err.is::<http::Error>() || err.is::<http::uri::InvalidUri>() {
+        return Severity::Fatal;
+    }
+
+    Severity::Transient
+}
+
+/// Capped exponential backoff with jitter, so replicas that fail together don't retry together.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Backoff {
+        Backoff {
+            base,
+            max,
+            attempt: 0
+        }
+    }
+
+    /// Returns a delay somewhere between half and all of `base * 2^attempt`, capped at `max`.
+    pub fn next_delay(&mut self) -> Duration {
+        let cap = self.base.saturating_mul(1 << self.attempt.min(16)).min(self.max);
+
+        self.attempt += 1;
+
+        let millis = cap.as_millis() as u64;
+
+        Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
@@ -0,0 +1,171 @@
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{warn, error};
+
+use crate::{time, shutdown::Shutdown, config::RotationConfig, retry::{self, Backoff, Severity}};
+
+// A connection that stayed up this long isn't part of a failure streak
+const HEALTHY_CONNECTION: u128 = 60000;
+
+// How many times each slot has delivered a key
+type Deliveries = HashMap<usize, u32>;
+
+/// A set of redundant connections that are each replaced after a fixed lifetime, staggered so that at least one is
+/// always open while another reconnects.
+///
+/// Every connection is given a slot; slot `n` cuts its first connection short by `n` staggers. Events received by
+/// more than one connection are filtered with `first_seen`, or `first_delivery` where the same event can legitimately
+/// arrive twice on one connection.
+pub struct Rotation {
+    lifetime: Duration,
+    stagger: Duration,
+    dedup_window: u128,
+    seen: Mutex<HashMap<String, u128>>,
+    // When each key was last delivered, and by whom
+    delivered: Mutex<HashMap<String, (u128, Deliveries)>>,
+    shutdown: Shutdown
+}
+
+impl Rotation {
+    /// `slots` is how many connections share the rotation, used to spread them out when no stagger is configured.
+    pub fn new(config: &RotationConfig, slots: usize, shutdown: Shutdown) -> Arc<Rotation> {
+        let lifetime = Duration::from_millis(config.lifetime_ms);
+
+        Arc::new(Rotation {
+            lifetime,
+            stagger: config.stagger_ms.map_or(lifetime / slots.max(1) as u32, Duration::from_millis),
+            dedup_window: config.dedup_window_ms as u128,
+            seen: Mutex::new(HashMap::new()),
+            delivered: Mutex::new(HashMap::new()),
+            shutdown
+        })
+    }
+
+    /// How long the connection in `slot` should stay open.
+    pub fn lifetime(&self, slot: usize, first: bool) -> Duration {
+        if !first {
+            return self.lifetime;
+        }
+
+        let offset = self.stagger.as_millis().saturating_mul(slot as u128) % self.lifetime.as_millis();
+
+        self.lifetime - Duration::from_millis(offset as u64)
+    }
+
+    /// Returns whether no connection has reported `key` within the dedup window, remembering it if so.
+    pub async fn first_seen(&self, key: String) -> bool {
+        let now = time();
+
+        let mut seen = self.seen.lock().await;
+
+        seen.retain(|_, at| now - *at < self.dedup_window);
+
+        seen.insert(key, now).is_none()
+    }
+
+    /// Returns whether the connection in `slot` is the first to deliver this copy of `key` within the dedup window.
+    ///
+    /// Unlike `first_seen`, a connection repeating a key isn't a duplicate: it's only dropped while another connection
+    /// has already delivered it at least as many times.
+    pub async fn first_delivery(&self, key: String, slot: usize) -> bool {
+        let now = time();
+
+        let mut delivered = self.delivered.lock().await;
+
+        delivered.retain(|_, (at, _)| now - *at < self.dedup_window);
+
+        let (at, counts) = delivered.entry(key).or_default();
+
+        *at = now;
+
+        let count = counts.entry(slot).or_default();
+
+        *count += 1;
+
+        let count = *count;
+
+        counts.iter().all(|(other, other_count)| *other == slot || *other_count < count)
+    }
+
+    /// Keeps the connection in `slot` going until a fatal error, which is returned, or shutdown. `connect` is called with
+    /// the lifetime of each connection, and should return once it's closed; transient failures are retried with backoff.
+    pub async fn run<F, Fut>(&self, slot: usize, mut connect: F) -> Result<(), Box<dyn Error + Send + Sync>>
+    where
+        F: FnMut(Duration) -> Fut,
+        Fut: Future<Output = Result<(), Box<dyn Error + Send + Sync>>>
+    {
+        let mut backoff = Backoff::new(Duration::from_millis(2000), Duration::from_millis(300000));
+
+        let mut first = true;
+
+        while !self.shutdown.is_requested() {
+            let start = time();
+
+            let result = connect(self.lifetime(slot, first)).await;
+
+            first = false;
+
+            match result {
+                Ok(()) => {
+                    backoff.reset();
+
+                    self.shutdown.until(tokio::time::sleep(Duration::from_millis(2000))).await;
+                }
+                Err(err) if retry::classify(err.as_ref()) == Severity::Fatal => {
+                    error!(%err, "giving up");
+
+                    return Err(err);
+                }
+                Err(err) => {
+                    if time() - start > HEALTHY_CONNECTION {
+                        backoff.reset();
+                    }
+
+                    let delay = backoff.next_delay();
+
+                    warn!(%err, retry_ms = delay.as_millis() as u64, "connection failed");
+
+                    self.shutdown.until(tokio::time::sleep(delay)).await;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotation() -> Arc<Rotation> {
+        Rotation::new(&RotationConfig {
+            replicas: 2,
+            lifetime_ms: 3600000,
+            stagger_ms: None,
+            dedup_window_ms: 60000
+        }, 2, Shutdown::new())
+    }
+
+    #[tokio::test]
+    async fn first_delivery_only_drops_copies_from_other_slots() {
+        let rotation = rotation();
+
+        assert!(rotation.first_delivery("a".to_owned(), 0).await);
+        assert!(!rotation.first_delivery("a".to_owned(), 1).await);
+
+        // A real repeat on the same connection, then the other connection's copy of it
+        assert!(rotation.first_delivery("a".to_owned(), 0).await);
+        assert!(!rotation.first_delivery("a".to_owned(), 1).await);
+
+        // The other connection getting ahead of the first
+        assert!(rotation.first_delivery("a".to_owned(), 1).await);
+        assert!(!rotation.first_delivery("a".to_owned(), 0).await);
+
+        assert!(rotation.first_delivery("b".to_owned(), 1).await);
+    }
+}
@@ -0,0 +1,222 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::collections::HashSet;
+use std::time::Duration;
+use std::path::{Path, PathBuf};
+use tokio::sync::{Mutex, Notify};
+use serde::{Serialize, Deserialize};
+use tracing::{info, warn, instrument, Instrument};
+
+use crate::{time, TMP_FILE_REVISION, queue::LinkKind, retry::{self, Severity}, watch::Watcher};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+const SCHEDULE_FILE: &str = "schedule.json";
+
+// A task that failed with a transient error is tried again after this long
+const RETRY_DELAY: u128 = 60000;
+
+/// Something to do with a post once its time comes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Action {
+    /// Post it if it's still there, e.g. after holding back a question from a low-reputation user
+    Recheck,
+    /// Wait for it to show up on the API again, after it didn't the first time
+    Retry
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Task {
+    seq: u64,
+    pub at: u128,
+    pub route_id: String,
+    pub kind: LinkKind,
+    pub post_id: String,
+    pub action: Action
+}
+
+impl Task {
+    pub fn new(at: u128, route_id: &str, kind: LinkKind, post_id: &str, action: Action) -> Task {
+        Task {
+            seq: 0,
+            at,
+            route_id: route_id.to_owned(),
+            kind,
+            post_id: post_id.to_owned(),
+            action
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedSchedule {
+    revision: String,
+    next_seq: u64,
+    tasks: Vec<Task>
+}
+
+struct ScheduleState {
+    next_seq: u64,
+    tasks: Vec<Task>,
+    // Tasks being run right now, which stay saved until they finish
+    running: HashSet<u64>
+}
+
+/// Delayed work on posts, written to `schedule.json` in the state directory whenever it changes so that a restart
+/// doesn't lose anything that was waiting. Tasks that fell due while the bot was down run as soon as it's back.
+pub struct Scheduler {
+    state_dir: PathBuf,
+    state: Mutex<ScheduleState>,
+    notify: Notify
+}
+
+impl Scheduler {
+    pub fn load(state_dir: &Path) -> Arc<Scheduler> {
+        let saved = std::fs::read_to_string(state_dir.join(SCHEDULE_FILE)).ok()
+            .and_then(|json| serde_json::from_str::<SavedSchedule>(&json).ok())
+            .filter(|saved| saved.revision == TMP_FILE_REVISION);
+
+        let state = match saved {
+            Some(saved) => {
+                info!(pending = saved.tasks.len(), "restored schedule");
+
+                ScheduleState {
+                    next_seq: saved.next_seq,
+                    tasks: saved.tasks,
+                    running: HashSet::new()
+                }
+            }
+            None => ScheduleState {
+                next_seq: 0,
+                tasks: Vec::new(),
+                running: HashSet::new()
+            }
+        };
+
+        Arc::new(Scheduler {
+            state_dir: state_dir.to_owned(),
+            state: Mutex::new(state),
+            notify: Notify::new()
+        })
+    }
+
+    /// Every task that hasn't finished yet.
+    pub async fn pending(&self) -> Vec<Task> {
+        self.state.lock().await.tasks.clone()
+    }
+
+    pub async fn schedule(&self, mut task: Task) {
+        {
+            let mut state = self.state.lock().await;
+
+            task.seq = state.next_seq;
+            state.next_seq += 1;
+
+            info!(seq = task.seq, action = ?task.action, kind = task.kind.noun(1), post_id = %task.post_id, route = %task.route_id, in_s = task.at.saturating_sub(time()) / 1000, "scheduled");
+
+            state.tasks.push(task);
+
+            self.save(&state).await;
+        }
+
+        self.notify.notify_one();
+    }
+
+    async fn finish(&self, seq: u64) {
+        let mut state = self.state.lock().await;
+
+        state.running.remove(&seq);
+        state.tasks.retain(|task| task.seq != seq);
+
+        self.save(&state).await;
+    }
+
+    /// Runs tasks as they fall due, until shutdown. Tasks still running then stay saved, and run again on the next start.
+    #[instrument(name = "scheduler", skip_all)]
+    pub async fn run(self: Arc<Self>, watcher: Arc<Watcher>) -> Result<()> {
+        let shutdown = watcher.shutdown();
+
+        // Anything left running by a previous run of this loop was lost with it
+        self.state.lock().await.running.clear();
+
+        loop {
+            let (due, next) = {
+                let mut state = self.state.lock().await;
+
+                let now = time();
+
+                let due = state.tasks.iter().filter(|task| task.at <= now && !state.running.contains(&task.seq)).cloned().collect::<Vec<Task>>();
+
+                state.running.extend(due.iter().map(|task| task.seq));
+
+                let next = state.tasks.iter().filter(|task| !state.running.contains(&task.seq)).map(|task| task.at).min();
+
+                (due, next)
+            };
+
+            for task in due {
+                let (scheduler, watcher) = (Arc::clone(&self), Arc::clone(&watcher));
+
+                tokio::spawn(async move {
+                    match watcher.run_scheduled(&task).await {
+                        Ok(()) => (),
+                        Err(err) if retry::classify(err.as_ref()) == Severity::Transient => {
+                            warn!(seq = task.seq, %err, retry_s = RETRY_DELAY / 1000, "task failed, retrying");
+
+                            scheduler.schedule(Task {
+                                at: time() + RETRY_DELAY,
+                                ..task.clone()
+                            }).await;
+                        }
+                        Err(err) => warn!(seq = task.seq, %err, "task failed, dropping")
+                    }
+
+                    scheduler.finish(task.seq).await;
+                }.in_current_span());
+            }
+
+            let woken = match next {
+                Some(at) => {
+                    let wait = Duration::from_millis(at.saturating_sub(time()) as u64);
+
+                    shutdown.until(async {
+                        tokio::select! {
+                            _ = tokio::time::sleep(wait) => (),
+                            _ = self.notify.notified() => ()
+                        }
+                    }).await
+                }
+                None => shutdown.until(self.notify.notified()).await
+            };
+
+            if woken.is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    pub async fn flush(&self) {
+        self.save(&*self.state.lock().await).await;
+    }
+
+    async fn save(&self, state: &ScheduleState) {
+        let saved = SavedSchedule {
+            revision: TMP_FILE_REVISION.to_string(),
+            next_seq: state.next_seq,
+            tasks: state.tasks.clone()
+        };
+
+        let result: Result<()> = async {
+            tokio::fs::create_dir_all(&self.state_dir).await?;
+            tokio::fs::write(self.state_dir.join(SCHEDULE_FILE), serde_json::to_string(&saved)?).await?;
+
+            Ok(())
+        }.await;
+
+        if let Err(err) = result {
+            warn!(%err, "failed to save schedule");
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+
+/// Tells every task of one [`run`](crate::run) to wind down. Clones share the same state, so a caller can keep one to
+/// stop the bot it started.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>
+}
+
+#[derive(Default)]
+struct Inner {
+    requested: AtomicBool,
+    notify: Notify
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Tells every task to wind down.
+    pub fn request(&self) {
+        self.inner.requested.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Completes once shutdown has been requested.
+    pub async fn requested(&self) {
+        loop {
+            // Created before checking, so a request in between isn't missed
+            let notified = self.inner.notify.notified();
+
+            if self.is_requested() {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Runs `future` unless shutdown is requested first, in which case it's dropped and `None` is returned.
+    pub async fn until<F: Future>(&self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = self.requested() => None
+        }
+    }
+}
+
+/// Waits for SIGTERM or SIGINT.
+pub async fn signal_received() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    tokio::select! {
+        _ = terminate.recv() => (),
+        _ = interrupt.recv() => ()
+    }
+
+    Ok(())
+}
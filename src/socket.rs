@@ -0,0 +1,340 @@
+use std::{error::Error, fmt};
+use futures::{Stream, StreamExt, SinkExt};
+use serde::{Deserialize, Deserializer};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite::{self, protocol::Message}};
+
+use crate::config::QuestionEvent;
+
+/// The network-wide feed of questions with new activity
+pub const FIREHOSE_ACTION: &str = "155-questions-active";
+
+/// A frame as qa.sockets sends it, with the payload still encoded as a JSON string.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Frame {
+    pub action: String,
+    pub data: String
+}
+
+/// A frame that couldn't be decoded. The frame is skipped; the socket stays up.
+#[derive(Debug)]
+pub enum DecodeError {
+    UnknownAction(String),
+    UnknownEvent {
+        action: String,
+        event: String
+    },
+    BadPayload {
+        action: String,
+        reason: String
+    }
+}
+
+impl Error for DecodeError {}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnknownAction(action) => write!(f, "unknown action {}", action),
+            DecodeError::UnknownEvent { action, event } => write!(f, "unknown event {} on {}", event, action),
+            DecodeError::BadPayload { action, reason } => write!(f, "bad payload on {} ({})", action, reason)
+        }
+    }
+}
+
+// IDs arrive as strings on some feeds and numbers on others
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawId {
+    Number(u64),
+    String(String)
+}
+
+fn id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match RawId::deserialize(deserializer)? {
+        RawId::Number(id) => Ok(id),
+        RawId::String(id) => id.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+fn optional_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "id")] u64);
+
+    Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(id)| id))
+}
+
+/// A question as announced on the newest, tag and active feeds. Only `id` is always present.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuestionSummary {
+    #[serde(deserialize_with = "id")]
+    pub id: u64,
+    #[serde(default)]
+    pub site_base_host_address: Option<String>,
+    #[serde(default)]
+    pub title_encoded_fancy: Option<String>,
+    // Not read by the bot yet, but decoded so handlers can use them without touching the socket code
+    #[serde(default)]
+    pub body_summary: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub last_activity_date: Option<u64>,
+    #[serde(default)]
+    pub owner_display_name: Option<String>,
+    #[serde(default)]
+    pub owner_url: Option<String>
+}
+
+/// Something that happened on a single question or one of its answers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Activity {
+    AnswerAdd {
+        answer_id: u64
+    },
+    PostEdit {
+        post_id: u64
+    },
+    CommentAdd {
+        post_id: u64,
+        comment_id: u64
+    },
+    Score {
+        post_id: u64,
+        score: i64
+    },
+    Closed {
+        post_id: u64
+    },
+    Reopened {
+        post_id: u64
+    }
+}
+
+impl Activity {
+    pub fn event(&self) -> QuestionEvent {
+        match self {
+            Activity::AnswerAdd { .. } => QuestionEvent::AnswerAdd,
+            Activity::PostEdit { .. } => QuestionEvent::PostEdit,
+            Activity::CommentAdd { .. } => QuestionEvent::CommentAdd,
+            Activity::Score { .. } => QuestionEvent::Score,
+            Activity::Closed { .. } => QuestionEvent::Close,
+            Activity::Reopened { .. } => QuestionEvent::Reopen
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawActivity {
+    a: String,
+    #[serde(default, deserialize_with = "optional_id")]
+    id: Option<u64>,
+    #[serde(default, deserialize_with = "optional_id")]
+    answerid: Option<u64>,
+    #[serde(default, deserialize_with = "optional_id")]
+    commentid: Option<u64>,
+    #[serde(default)]
+    score: Option<i64>
+}
+
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Heartbeat,
+    /// From `{site}-questions-newest` and `{site}-questions-newest-tag-{tag}`
+    NewestQuestion(QuestionSummary),
+    /// From `155-questions-active`
+    ActiveQuestion(QuestionSummary),
+    /// From `{site}-question-{id}`
+    Activity(Activity)
+}
+
+fn parse<'a, T: Deserialize<'a>>(action: &str, data: &'a str) -> Result<T, DecodeError> {
+    serde_json::from_str(data).map_err(|err| DecodeError::BadPayload {
+        action: action.to_owned(),
+        reason: err.to_string()
+    })
+}
+
+fn decode_activity(action: &str, data: &str) -> Result<Activity, DecodeError> {
+    let raw: RawActivity = parse(action, data)?;
+
+    let missing = |field: &str| DecodeError::BadPayload {
+        action: action.to_owned(),
+        reason: format!("{} without {}", raw.a, field)
+    };
+
+    let post_id = raw.id.ok_or_else(|| missing("id"));
+
+    Ok(match raw.a.as_str() {
+        "answer-add" => Activity::AnswerAdd { answer_id: raw.answerid.ok_or_else(|| missing("answerid"))? },
+        "post-edit" => Activity::PostEdit { post_id: post_id? },
+        "comment-add" => Activity::CommentAdd { post_id: post_id?, comment_id: raw.commentid.ok_or_else(|| missing("commentid"))? },
+        "score" => Activity::Score { post_id: post_id?, score: raw.score.ok_or_else(|| missing("score"))? },
+        "post-closed" => Activity::Closed { post_id: post_id? },
+        "post-reopened" => Activity::Reopened { post_id: post_id? },
+        _ => return Err(DecodeError::UnknownEvent {
+            action: action.to_owned(),
+            event: raw.a.clone()
+        })
+    })
+}
+
+/// Decodes a frame's payload according to the family of action it came from.
+pub fn decode(frame: &Frame) -> Result<Payload, DecodeError> {
+    let action = frame.action.as_str();
+
+    if action == "hb" {
+        return Ok(Payload::Heartbeat);
+    }
+
+    if action == FIREHOSE_ACTION {
+        return Ok(Payload::ActiveQuestion(parse(action, &frame.data)?));
+    }
+
+    match action.split_once('-') {
+        Some((_, rest)) if rest.starts_with("questions-newest") => Ok(Payload::NewestQuestion(parse(action, &frame.data)?)),
+        Some((_, rest)) if rest.starts_with("question-") => Ok(Payload::Activity(decode_activity(action, &frame.data)?)),
+        _ => Err(DecodeError::UnknownAction(action.to_owned()))
+    }
+}
+
+/// A connection to qa.sockets, subscribed to some actions.
+pub struct Subscription {
+    ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>
+}
+
+impl Subscription {
+    /// Connects to qa.sockets at `url`, e.g. `wss://qa.sockets.stackexchange.com/`, and subscribes to each of
+    /// `actions`.
+    pub async fn connect<S: AsRef<str>>(url: &str, actions: &[S]) -> Result<Subscription, tungstenite::Error> {
+        let mut ws_stream = tokio_tungstenite::connect_async(url).await?.0;
+
+        for action in actions {
+            ws_stream.send(Message::Text(action.as_ref().to_owned())).await?;
+        }
+
+        Ok(Subscription {
+            ws_stream
+        })
+    }
+
+    /// The next text frame as it was sent, or `None` once the socket has closed. Heartbeats are left to the caller to
+    /// answer with [`Subscription::pong`].
+    pub async fn next_text(&mut self) -> Option<Result<String, tungstenite::Error>> {
+        loop {
+            match self.ws_stream.next().await? {
+                Ok(Message::Text(string)) => return Some(Ok(string)),
+                Ok(_) => (),
+                Err(err) => return Some(Err(err))
+            }
+        }
+    }
+
+    /// Answers a heartbeat, without which qa.sockets drops the connection.
+    pub async fn pong(&mut self) -> Result<(), tungstenite::Error> {
+        self.ws_stream.send(Message::Text("pong".to_owned())).await
+    }
+
+    pub async fn close(&mut self) -> Result<(), tungstenite::Error> {
+        self.ws_stream.close(None).await
+    }
+
+    /// The next frame that isn't a heartbeat, decoded, or `None` once the socket has closed. Heartbeats are answered
+    /// along the way. A frame that can't be decoded is returned as an error, but the socket stays up.
+    pub async fn next(&mut self) -> Option<Result<(Frame, Payload), Box<dyn Error + Send + Sync>>> {
+        loop {
+            let string = match self.next_text().await? {
+                Ok(string) => string,
+                Err(err) => return Some(Err(Box::new(err)))
+            };
+
+            let frame: Frame = match serde_json::from_str(&string) {
+                Ok(frame) => frame,
+                Err(err) => return Some(Err(Box::new(err)))
+            };
+
+            match decode(&frame) {
+                Ok(Payload::Heartbeat) => {
+                    if let Err(err) = self.pong().await {
+                        return Some(Err(Box::new(err)));
+                    }
+                }
+                Ok(payload) => return Some(Ok((frame, payload))),
+                Err(err) => return Some(Err(Box::new(err)))
+            }
+        }
+    }
+
+    /// [`Subscription::next`] as a stream.
+    pub fn into_stream(self) -> impl Stream<Item = Result<(Frame, Payload), Box<dyn Error + Send + Sync>>> {
+        futures::stream::unfold(self, |mut subscription| async move {
+            subscription.next().await.map(|item| (item, subscription))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(action: &str, data: &str) -> Frame {
+        Frame {
+            action: action.to_owned(),
+            data: data.to_owned()
+        }
+    }
+
+    fn activity(data: &str) -> Result<Activity, DecodeError> {
+        match decode(&frame("200-question-5", data))? {
+            Payload::Activity(activity) => Ok(activity),
+            payload => panic!("not an activity: {:?}", payload)
+        }
+    }
+
+    #[test]
+    fn decodes_heartbeats() {
+        assert!(matches!(decode(&frame("hb", "")), Ok(Payload::Heartbeat)));
+    }
+
+    #[test]
+    fn decodes_question_feeds() {
+        let newest = decode(&frame("200-questions-newest", r#"{"id":"5","titleEncodedFancy":"Golf","tags":["code-golf"]}"#)).unwrap();
+
+        assert!(matches!(newest, Payload::NewestQuestion(question) if question.id == 5 && question.tags == ["code-golf"]));
+
+        let tagged = decode(&frame("200-questions-newest-tag-code-golf", r#"{"id":6}"#)).unwrap();
+
+        assert!(matches!(tagged, Payload::NewestQuestion(question) if question.id == 6 && question.title_encoded_fancy.is_none()));
+
+        let active = decode(&frame(FIREHOSE_ACTION, r#"{"id":"7","siteBaseHostAddress":"codegolf.stackexchange.com"}"#)).unwrap();
+
+        assert!(matches!(active, Payload::ActiveQuestion(question) if question.id == 7));
+    }
+
+    #[test]
+    fn decodes_activity_with_string_or_numeric_ids() {
+        assert_eq!(activity(r#"{"a":"answer-add","id":5,"answerid":"8"}"#).unwrap(), Activity::AnswerAdd { answer_id: 8 });
+        assert_eq!(activity(r#"{"a":"post-edit","id":"5"}"#).unwrap(), Activity::PostEdit { post_id: 5 });
+        assert_eq!(activity(r#"{"a":"comment-add","id":5,"commentid":9}"#).unwrap(), Activity::CommentAdd { post_id: 5, comment_id: 9 });
+        assert_eq!(activity(r#"{"a":"score","id":"5","score":-2}"#).unwrap(), Activity::Score { post_id: 5, score: -2 });
+        assert_eq!(activity(r#"{"a":"post-closed","id":5}"#).unwrap(), Activity::Closed { post_id: 5 });
+        assert_eq!(activity(r#"{"a":"post-reopened","id":"5"}"#).unwrap(), Activity::Reopened { post_id: 5 });
+    }
+
+    #[test]
+    fn rejects_unknown_actions_and_events() {
+        assert!(matches!(decode(&frame("hello", "{}")), Err(DecodeError::UnknownAction(action)) if action == "hello"));
+        assert!(matches!(decode(&frame("200-answers-newest", "{}")), Err(DecodeError::UnknownAction(_))));
+        assert!(matches!(activity(r#"{"a":"post-deleted","id":5}"#), Err(DecodeError::UnknownEvent { event, .. }) if event == "post-deleted"));
+    }
+
+    #[test]
+    fn rejects_malformed_data() {
+        assert!(matches!(decode(&frame("200-questions-newest", "not json")), Err(DecodeError::BadPayload { .. })));
+        assert!(matches!(decode(&frame("200-questions-newest", r#"{"id":"five"}"#)), Err(DecodeError::BadPayload { .. })));
+        assert!(matches!(decode(&frame(FIREHOSE_ACTION, r#"{"title":"no id"}"#)), Err(DecodeError::BadPayload { .. })));
+        assert!(matches!(activity(r#"{"a":"answer-add","id":5}"#), Err(DecodeError::BadPayload { reason, .. }) if reason == "answer-add without answerid"));
+        assert!(matches!(activity(r#"{"a":"score","id":5}"#), Err(DecodeError::BadPayload { .. })));
+    }
+}
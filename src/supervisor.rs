@@ -0,0 +1,176 @@
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{time, shutdown::Shutdown, retry::Backoff};
+
+// A task that ran this long before failing starts its backoff over
+const HEALTHY_RUN: u128 = 600000;
+
+const REPORT_INTERVAL: Duration = Duration::from_millis(900000);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TaskState {
+    Running,
+    /// Failed and waiting to be restarted
+    Restarting,
+    /// Returned without an error, and won't be restarted
+    Stopped
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskHealth {
+    pub state: TaskState,
+    pub started: u128,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    pub last_error_time: Option<u128>
+}
+
+/// Owns the bot's long-running tasks, restarting them with backoff when they fail or panic, and keeps track of how
+/// each one is doing.
+pub struct Supervisor {
+    health: Mutex<BTreeMap<String, TaskHealth>>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    shutdown: Shutdown
+}
+
+impl Supervisor {
+    pub fn new(shutdown: Shutdown) -> Arc<Supervisor> {
+        Arc::new(Supervisor {
+            health: Mutex::new(BTreeMap::new()),
+            handles: Mutex::new(Vec::new()),
+            shutdown
+        })
+    }
+
+    /// Runs `task` under the name `name`, calling it again whenever a run fails.
+    pub async fn spawn<F, Fut>(self: &Arc<Self>, name: &str, task: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send + 'static
+    {
+        let name = name.to_owned();
+        let supervisor = Arc::clone(self);
+
+        let handle = tokio::spawn(async move {
+            let mut backoff = Backoff::new(Duration::from_millis(5000), Duration::from_millis(1800000));
+
+            loop {
+                let started = time();
+
+                supervisor.update(&name, |health| {
+                    health.state = TaskState::Running;
+                    health.started = started;
+                }).await;
+
+                // Each run gets its own task so a panic is caught here rather than taking the supervisor with it
+                let error = match tokio::spawn(task()).await {
+                    Ok(Ok(())) => {
+                        info!(task = %name, "stopped");
+
+                        supervisor.update(&name, |health| health.state = TaskState::Stopped).await;
+
+                        return;
+                    }
+                    Ok(Err(err)) => err.to_string(),
+                    Err(err) => format!("panicked: {}", err)
+                };
+
+                if supervisor.shutdown.is_requested() {
+                    info!(task = %name, %error, "stopped while shutting down");
+
+                    supervisor.update(&name, |health| health.state = TaskState::Stopped).await;
+
+                    return;
+                }
+
+                if time() - started > HEALTHY_RUN {
+                    backoff.reset();
+                }
+
+                let delay = backoff.next_delay();
+
+                let restarts = supervisor.update(&name, |health| {
+                    health.state = TaskState::Restarting;
+                    health.restarts += 1;
+                    health.last_error = Some(error.clone());
+                    health.last_error_time = Some(time());
+                }).await.restarts;
+
+                warn!(task = %name, %error, restarts, delay_ms = delay.as_millis() as u64, "failed, restarting");
+
+                if supervisor.shutdown.until(tokio::time::sleep(delay)).await.is_none() {
+                    supervisor.update(&name, |health| health.state = TaskState::Stopped).await;
+
+                    return;
+                }
+            }
+        });
+
+        self.handles.lock().await.push(handle);
+    }
+
+    async fn update(&self, name: &str, f: impl FnOnce(&mut TaskHealth)) -> TaskHealth {
+        let mut tasks = self.health.lock().await;
+
+        let health = tasks.entry(name.to_owned()).or_insert_with(|| TaskHealth {
+            state: TaskState::Running,
+            started: time(),
+            restarts: 0,
+            last_error: None,
+            last_error_time: None
+        });
+
+        f(health);
+
+        health.clone()
+    }
+
+    pub async fn health(&self) -> BTreeMap<String, TaskHealth> {
+        self.health.lock().await.clone()
+    }
+
+    /// Periodically logs a line per task that isn't running, or has been restarted.
+    pub fn report(self: &Arc<Self>) {
+        let supervisor = Arc::clone(self);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REPORT_INTERVAL);
+
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+
+                let health = supervisor.health().await;
+                let running = health.values().filter(|health| health.state == TaskState::Running).count();
+
+                info!(running, total = health.len(), "task report");
+
+                for (name, health) in health {
+                    if health.state != TaskState::Running || health.restarts > 0 {
+                        warn!(task = %name, state = ?health.state, restarts = health.restarts, last_error = health.last_error.as_deref(), "unhealthy task");
+                    }
+                }
+            }
+        });
+    }
+
+    /// Waits for every task to stop for good.
+    pub async fn join(&self) {
+        let handles = std::mem::take(&mut *self.handles.lock().await);
+
+        for handle in handles {
+            let _ = handle.await;
+        }
+    }
+}
@@ -1,414 +1,909 @@
 use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
 use tokio::sync::Mutex;
-use serde::Deserialize;
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
 use std::time::Duration;
 
-use futures::{StreamExt, SinkExt};
-use tokio_tungstenite::tungstenite::protocol::Message;
+use tracing::{info, warn, debug, instrument, Instrument};
 
-use crate::{time, Ids, login::User, Config};
+use crate::{time, TMP_FILE_REVISION, Ids, metrics, shutdown::Shutdown, capture::{self, Event}, api::{Api, Priority}, login::{self, User}, queue::{MessageQueue, LinkKind}, rotate::Rotation, scheduler::{Scheduler, Task, Action}, Config};
+use crate::config::{RouteConfig, SiteConfig, WatchSocketConfig, WatchSocketConfigType, WatchSource, QuestionEvent, BackfillOverflow, MissingPost};
+use crate::socket::{self, Frame, Payload, DecodeError, Activity, Subscription, FIREHOSE_ACTION};
 
-async fn post(room_id: u64, text: String, user: Arc<User>) {
-    async fn try_post(room_id: u64, text: &String, user: Arc<User>) -> Option<u8> {
-        let response = user.client.post(format!("https://chat.stackexchange.com/chats/{}/messages/new", room_id)).form(&[
-            ("text", text),
-            ("fkey", &user.fkey)
-        ]).send().await.unwrap();
-        
-        if !response.status().is_success() {
-            if response.status().as_u16() == 409 {
-                let text = response.text().await.unwrap();
-                
-                if text.starts_with("You can perform this action again in ") {
-                    return Some(text[37..].split_once(' ').unwrap().0.parse::<u8>().unwrap());
-                }
-            }
-        }
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// Questions from users with less reputation than this are held back for a while, in case they get deleted as spam
+const LOW_REP: u64 = 10;
+const LOW_REP_HOLD: Duration = Duration::from_millis(5 * 60 * 1000);
+
+const FEEDS_FILE: &str = "feeds.json";
+
+// Without a saved last-seen time, catch-up looks back this far
+const DEFAULT_CATCH_UP: u128 = 1200000;
+
+// Creation dates only have second precision, and the API can lag the socket by a little
+const CATCH_UP_MARGIN: u128 = 20000;
+
+const PAGE_SIZE: u32 = 30;
+
+// Catch-up stops paging after this many pages, whatever the last-seen time
+const MAX_PAGES: u32 = 10;
 
-        None
+/// The qa.sockets action a watch socket subscribes to, e.g. `200-questions-newest` or `202-question-2140`.
+fn socket_action(site: &SiteConfig, watch_socket: &WatchSocketConfig) -> String {
+    match &watch_socket.config {
+        WatchSocketConfigType::Questions => format!("{}-questions-newest", site.websocket_id),
+        WatchSocketConfigType::Answers { question_id } | WatchSocketConfigType::QuestionActivity { question_id, .. } => format!("{}-question-{}", site.websocket_id, question_id),
+        WatchSocketConfigType::Tag { tag } => format!("{}-questions-newest-tag-{}", site.websocket_id, tag),
+        WatchSocketConfigType::Active { .. } => FIREHOSE_ACTION.to_owned()
     }
-    
-    match try_post(room_id, &text, Arc::clone(&user)).await {
-        Some(cooldown) => {
-            println!("cooldown: {}s", cooldown);
-            
-            tokio::time::sleep(Duration::from_millis((cooldown as u64) * 1000 + 2000)).await;
-            
-            try_post(room_id, &text, Arc::clone(&user)).await.xor(Some(0)).unwrap();
-        }
-        None => ()
+}
+
+
+#[derive(Debug)]
+struct NotOnApi {}
+
+impl std::error::Error for NotOnApi {}
+
+impl std::fmt::Display for NotOnApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "not on the API yet")
     }
 }
 
-#[derive(Deserialize)]
-struct APIWrapperWithItems {
-    items: Vec<serde_json::Value>
+/// What the API had to say about a post once it appeared.
+struct PostMeta {
+    // Missing for posts by deleted users
+    owner_reputation: Option<u64>,
+    created: u128
+}
+
+/// How long posts from the socket took to show up on the API.
+#[derive(Default)]
+struct ApiLatency {
+    found: u64,
+    missing: u64,
+    total_ms: u128,
+    max_ms: u128
 }
 
-#[derive(Deserialize)]
-struct APIQuestions {
-    items: Vec<APIQuestion>
+/// Whether a watch socket's posts can be found by polling the API, rather than only as they happen.
+fn is_pollable(watch_socket: &WatchSocketConfig) -> bool {
+    match &watch_socket.config {
+        WatchSocketConfigType::Active { .. } => false,
+        WatchSocketConfigType::QuestionActivity { events, .. } => events.contains(&QuestionEvent::AnswerAdd),
+        _ => true
+    }
 }
 
-#[derive(Deserialize)]
-struct APIQuestion {
-    creation_date: u128,
-    question_id: u64,
-    owner: Option<APIShallowUser>
+/// How a post first reached a route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Source {
+    Socket,
+    /// The API catch-up before each socket connects
+    CatchUp,
+    Poll,
+    Reconcile
 }
 
-#[derive(Deserialize, Clone, Copy)]
-struct APIShallowUser {
-    reputation: u64
+struct Feed {
+    kind: LinkKind,
+    // ID and creation date, newest first
+    posts: Vec<(u64, u128)>,
+    has_more: bool,
+    backoff: Option<u64>,
+    quota_remaining: Option<u64>
 }
 
-#[derive(Deserialize)]
-struct APIAnswers {
-    items: Vec<APIAnswer>
+#[derive(Serialize, Deserialize)]
+struct SavedFeeds {
+    revision: String,
+    // Watch socket ID -> creation time of the newest post processed from it
+    last_seen: HashMap<String, u128>
 }
 
-#[derive(Deserialize)]
-struct APIAnswer {
-    creation_date: u128,
-    answer_id: u64
+/// What one fetch of a watch socket's feed turned up.
+pub struct FeedPoll {
+    pub new_posts: usize,
+    pub backoff: Option<u64>,
+    pub quota_remaining: Option<u64>
 }
 
-async fn wait_for_api(id: &str, is_answer: bool, site: &str, user: Arc<User>, config: Arc<Config>) -> u64 {
-    let start = time();
-    
-    async fn is_on_api(id: &str, is_answer: bool, site: &str, user: Arc<User>, config: Arc<Config>) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
-        let response_text = user.client.get(format!("https://api.stackexchange.com/2.3/{}/{}?site={}&key={}&filter=!)Q.zNIhl_-qxryCqQj5aX(Sp", if is_answer { "answers" } else { "questions" }, id, site, config.get_api_key())).send().await.unwrap().error_for_status().unwrap().text().await.unwrap();
+/// Everything the watch sockets need to turn qa.sockets events into chat messages, shared by every replica.
+pub struct Watcher {
+    config: Arc<Config>,
+    users: HashMap<String, Arc<User>>,
+    ids: Arc<Mutex<Ids>>,
+    queue: Arc<MessageQueue>,
+    api: Api,
+    api_client: reqwest::Client,
+    // qa.sockets action -> IDs of the routes subscribed to it
+    routes_by_action: HashMap<String, Vec<String>>,
+    rotation: Arc<Rotation>,
+    // qa.sockets action -> when any replica last got something other than a heartbeat on it, or when the watcher
+    // started
+    last_event: Mutex<HashMap<String, u128>>,
+    // Route ID -> how many posts each source delivered first
+    first_deliveries: Mutex<BTreeMap<String, BTreeMap<Source, u64>>>,
+    // Persisted to `feeds.json` in the state directory
+    last_seen: Mutex<HashMap<String, u128>>,
+    api_latency: Mutex<ApiLatency>,
+    scheduler: Arc<Scheduler>,
+    // Routes whose posts are claimed but not sent to chat
+    paused: Mutex<HashSet<String>>,
+    shutdown: Shutdown
+}
 
-        if !is_answer {
-            let response: APIQuestions = serde_json::from_str(&response_text).unwrap();
+impl Watcher {
+    pub fn new(config: Arc<Config>, users: HashMap<String, Arc<User>>, ids: Arc<Mutex<Ids>>, queue: Arc<MessageQueue>, scheduler: Arc<Scheduler>, shutdown: Shutdown) -> Result<Arc<Watcher>> {
+        let mut routes_by_action: HashMap<String, Vec<String>> = HashMap::new();
 
-            Ok(response.items.get(0).unwrap().owner.unwrap().reputation)
-        } else {
-            let response: APIWrapperWithItems = serde_json::from_str(&response_text).unwrap();
+        for (route_id, route) in config.get_route_configs() {
+            if route.watch_socket.source == WatchSource::Poll {
+                info!(watch_socket = route.watch_socket_id, site = %route.site.id, route = route_id, "polling");
+
+                continue;
+            }
+
+            let action = socket_action(route.site, route.watch_socket);
 
-            response.items.get(0).unwrap();
+            info!(%action, site = %route.site.id, route = route_id, "subscribing");
 
-            Ok(0)
+            routes_by_action.entry(action).or_default().push(route_id.to_owned());
         }
+
+        let last_seen = std::fs::read_to_string(config.get_state_dir().join(FEEDS_FILE)).ok()
+            .and_then(|json| serde_json::from_str::<SavedFeeds>(&json).ok())
+            .filter(|saved| saved.revision == TMP_FILE_REVISION)
+            .map_or_else(HashMap::new, |saved| saved.last_seen);
+
+        let last_event = routes_by_action.keys().map(|action| (action.clone(), time())).collect();
+
+        Ok(Arc::new(Watcher {
+            rotation: Rotation::new(&config.get_rotation().watch, config.get_rotation().watch.replicas, shutdown.clone()),
+            api: Api::new(config.get_api_key(), config.get_api(), &config.get_endpoints().api_root),
+            config,
+            users,
+            ids,
+            queue,
+            api_client: reqwest::ClientBuilder::new().user_agent(login::USER_AGENT).gzip(true).build()?,
+            routes_by_action,
+            last_event: Mutex::new(last_event),
+            first_deliveries: Mutex::new(BTreeMap::new()),
+            last_seen: Mutex::new(last_seen),
+            api_latency: Mutex::new(ApiLatency::default()),
+            scheduler,
+            paused: Mutex::new(HashSet::new()),
+            shutdown
+        }))
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config)
     }
-    
-    for _ in 0..4 {
-        if let Ok(rep) = is_on_api(id, is_answer, site, Arc::clone(&user), Arc::clone(&config)).await {
-            println!("wait_for_api took {}ms", time() - start);
 
-            return rep;
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    pub fn has_socket_actions(&self) -> bool {
+        !self.routes_by_action.is_empty()
+    }
+
+    /// Watch sockets that should be polled, at least while qa.sockets is down.
+    pub fn polled_watch_sockets(&self) -> Vec<String> {
+        let mut watch_sockets = self.config.get_route_configs().values()
+            .filter(|route| route.watch_socket.source != WatchSource::Socket && is_pollable(route.watch_socket))
+            .map(|route| route.watch_socket_id.to_owned())
+            .collect::<Vec<String>>();
+
+        watch_sockets.sort();
+        watch_sockets.dedup();
+
+        watch_sockets
+    }
+
+    /// Whether no replica has had an event on a watch socket's feed from qa.sockets for `silence`. Heartbeats don't
+    /// count, since qa.sockets keeps sending them on a connection whose subscriptions have quietly stopped delivering.
+    /// Each feed is judged on its own, so one busy feed doesn't hide another going quiet.
+    pub async fn socket_silent(&self, watch_socket_id: &str, silence: Duration) -> bool {
+        let watch_socket = &self.config.get_watch_sockets()[watch_socket_id];
+        let action = socket_action(&self.config.get_sites()[&watch_socket.site], watch_socket);
+
+        // Feeds that aren't subscribed to at all have nothing else to go on
+        self.last_event.lock().await.get(&action).is_none_or(|at| time().saturating_sub(*at) > silence.as_millis())
+    }
+
+    fn api_client(&self, route: &RouteConfig) -> &reqwest::Client {
+        if route.force_user_client_for_watch_socket {
+            &self.users[route.user_id].client
+        } else {
+            &self.api_client
         }
-        
-        tokio::time::sleep(Duration::from_millis(200)).await;
     }
-    
-    for _ in 0..4 {
-        if let Ok(rep) = is_on_api(id, is_answer, site, Arc::clone(&user), Arc::clone(&config)).await {
-            println!("wait_for_api took {}ms", time() - start);
 
-            return rep;
+    /// Marks a post as known in the route's room, returning whether it's new there, in which case `source` is credited
+    /// with delivering it.
+    async fn claim(&self, route: &RouteConfig<'_>, post_id: &str, source: Source) -> bool {
+        let new = self.ids.lock().await.insert(route.room.room_id(), &route.site.id, post_id);
+
+        if new {
+            *self.first_deliveries.lock().await.entry(route.id.to_owned()).or_default().entry(source).or_default() += 1;
         }
-        
-        tokio::time::sleep(Duration::from_millis(1000)).await;
+
+        new
     }
-    
-    panic!("Took too long to wait_for_api");
-}
 
-#[derive(Debug, Deserialize)]
-struct WatchData {
-    action: String,
-    data: String
-}
+    /// Records that a watch socket's posts have been processed up to `at`.
+    async fn mark_seen(&self, watch_socket_id: &str, at: u128) {
+        let mut last_seen = self.last_seen.lock().await;
 
-#[derive(Deserialize)]
-struct Question {
-    id: String
-}
+        let seen = last_seen.entry(watch_socket_id.to_owned()).or_default();
 
-#[derive(Deserialize)]
-struct Update {
-    a: String
-}
+        if *seen >= at {
+            return;
+        }
 
-#[derive(Deserialize)]
-struct AnswerAdd {
-    answerid: u64
-}
+        *seen = at;
 
-async fn connect_watch_ws(id: usize, ids: Arc<Mutex<Ids>>, user_main: Arc<User>, user_sandbox: Arc<User>, config: Arc<Config>, kill_offset: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut ws_stream = tokio_tungstenite::connect_async("wss://qa.sockets.stackexchange.com/").await?.0;
-    
-    ws_stream.send(Message::Text("200-questions-newest".to_owned())).await?;
-    ws_stream.send(Message::Text("202-questions-newest".to_owned())).await?;
-    ws_stream.send(Message::Text("202-question-2140".to_owned())).await?;
-    
-    // PLDI
-    
-    ws_stream.send(Message::Text("716-questions-newest".to_owned())).await?;
-    ws_stream.send(Message::Text("717-questions-newest".to_owned())).await?;
-    
-    println!("watch_{}: open", id);
-    
-    let duration = tokio::time::sleep(if kill_offset {
-        Duration::from_millis(720000)
-    } else {
-        Duration::from_millis(1440000)
-    });
-    
-    let mut watch = tokio::spawn(async move {
-        while let Some(msg_r) = ws_stream.next().await {
-            let msg = msg_r.unwrap();
-
-            match msg {
-                Message::Text(string) => {
-                    let data: WatchData = serde_json::from_str(&string).unwrap();
-
-                    if data.action == "hb" {
-                        ws_stream.send(Message::Text("pong".to_owned())).await.unwrap();
-                    } else {
-                        {
-                            let ids_clone = Arc::clone(&ids);
-                            let user_main_clone = Arc::clone(&user_main);
-                            let user_sandbox_clone = Arc::clone(&user_sandbox);
-                            let config_clone = Arc::clone(&config);
-
-                            tokio::spawn(async move {
-                                let ids = ids_clone;
-                                let user_main = user_main_clone;
-                                let user_sandbox = user_sandbox_clone;
-                                let config = config_clone;
-
-                                match data.action.as_str() {
-                                    "200-questions-newest" => {
-                                        let question: Question = serde_json::from_str(&data.data).unwrap();
-        
-                                        println!("watch_{}: 200-questions-newest: {}", id, question.id);
-                                        
-                                        if ids.lock().await.p_200.insert(question.id.clone()) {
-                                            let rep = wait_for_api(&question.id, false, "codegolf", Arc::clone(&user_main), Arc::clone(&config)).await;
-                                            
-                                            if rep < 10 {
-                                                println!("watch_{}: question {}: User rep is {} (<10), delaying...", id, question.id, rep);
-    
-                                                tokio::time::sleep(Duration::from_millis(5 * 60 * 1000)).await;
-    
-                                                let response: APIQuestions = serde_json::from_str(&(user_main.client.get(format!("https://api.stackexchange.com/2.3/questions/{}?site=codegolf&key={}&filter=!)Q.zNIhl_-qxryCqQj5aX(Sp", question.id, config.get_api_key())).send().await.unwrap().error_for_status().unwrap().text().await.unwrap())).unwrap();
-    
-                                                if response.items.is_empty() {
-                                                    println!("watch_{}: question {}: seems to be deleted now", id, question.id);
-
-                                                    return;
-                                                }
-                                            }
-    
-                                            post(240, format!("https://codegolf.stackexchange.com/q/{}", question.id), Arc::clone(&user_main)).await;
-                                            
-                                            println!("watch_{}: posted question {}", id, question.id);
-                                        }
-                                    }
-                                    "202-questions-newest" => {
-                                        let question: Question = serde_json::from_str(&data.data).unwrap();
-        
-                                        println!("watch_{}: 202-questions-newest: {}", id, question.id);
-        
-                                        if ids.lock().await.p_202.insert(question.id.clone()) {
-                                            let rep = wait_for_api(&question.id, false, "codegolf.meta", Arc::clone(&user_main), Arc::clone(&config)).await;
-                                            
-                                            if rep < 10 {
-                                                println!("watch_{}: question {}: User rep is {} (<10), delaying...", id, question.id, rep);
-    
-                                                tokio::time::sleep(Duration::from_millis(5 * 60 * 1000)).await;
-    
-                                                let response: APIQuestions = serde_json::from_str(&(user_main.client.get(format!("https://api.stackexchange.com/2.3/questions/{}?site=codegolf.meta&key={}&filter=!)Q.zNIhl_-qxryCqQj5aX(Sp", question.id, config.get_api_key())).send().await.unwrap().error_for_status().unwrap().text().await.unwrap())).unwrap();
-    
-                                                if response.items.is_empty() {
-                                                    println!("watch_{}: question {}: seems to be deleted now", id, question.id);
-
-                                                    return;
-                                                }
-                                            }
-                                            
-                                            post(240, format!("https://codegolf.meta.stackexchange.com/q/{}", question.id), Arc::clone(&user_main)).await;
-                                            
-                                            println!("watch_{}: posted question {}", id, question.id);
-                                        }
-                                    }
-                                    "202-question-2140" => {
-                                        let update: Update = serde_json::from_str(&data.data).unwrap();
-        
-                                        match update.a.as_str() {
-                                            "answer-add" => {
-                                                let answer: AnswerAdd = serde_json::from_str(&data.data).unwrap();
-        
-                                                println!("watch_{}: 202-question-2104 answer-add: {}", id, answer.answerid);
-        
-                                                if ids.lock().await.p_202.insert(answer.answerid.to_string()) {
-                                                    let _rep = wait_for_api(&answer.answerid.to_string(), true, "codegolf.meta", Arc::clone(&user_sandbox), Arc::clone(&config)).await;
-                                                    
-                                                    post(240, format!("https://codegolf.meta.stackexchange.com/a/{}", answer.answerid), Arc::clone(&user_sandbox)).await;
-        
-                                                    println!("watch_{}: posted answer {}", id, answer.answerid);
-                                                }
-                                            },
-                                            _ => ()
-                                        }
-                                    }
-                                    // PLDI
-                                    "716-questions-newest" => {
-                                        let question: Question = serde_json::from_str(&data.data).unwrap();
-        
-                                        println!("watch_{}: 716-questions-newest: {}", id, question.id);
-                                        
-                                        if ids.lock().await.p_716.insert(question.id.clone()) {
-                                            let rep = wait_for_api(&question.id, false, "languagedesign", Arc::clone(&user_main), Arc::clone(&config)).await;
-                                            
-                                            if rep < 10 {
-                                                println!("watch_{}: question {}: User rep is {} (<10), delaying...", id, question.id, rep);
-    
-                                                tokio::time::sleep(Duration::from_millis(5 * 60 * 1000)).await;
-    
-                                                let response: APIQuestions = serde_json::from_str(&(user_main.client.get(format!("https://api.stackexchange.com/2.3/questions/{}?site=languagedesign&key={}&filter=!)Q.zNIhl_-qxryCqQj5aX(Sp", question.id, config.get_api_key())).send().await.unwrap().error_for_status().unwrap().text().await.unwrap())).unwrap();
-    
-                                                if response.items.is_empty() {
-                                                    println!("watch_{}: question {}: seems to be deleted now", id, question.id);
-
-                                                    return;
-                                                }
-                                            }
-                                            
-                                            post(146046, format!("https://languagedesign.stackexchange.com/q/{}", question.id), Arc::clone(&user_main)).await;
-                                            
-                                            println!("watch_{}: posted question {}", id, question.id);
-                                        }
-                                    }
-                                    "717-questions-newest" => {
-                                        let question: Question = serde_json::from_str(&data.data).unwrap();
-        
-                                        println!("watch_{}: 717-questions-newest: {}", id, question.id);
-        
-                                        if ids.lock().await.p_717.insert(question.id.clone()) {
-                                            let rep = wait_for_api(&question.id, false, "languagedesign.meta", Arc::clone(&user_main), Arc::clone(&config)).await;
-                                            
-                                            if rep < 10 {
-                                                println!("watch_{}: question {}: User rep is {} (<10), delaying...", id, question.id, rep);
-    
-                                                tokio::time::sleep(Duration::from_millis(5 * 60 * 1000)).await;
-    
-                                                let response: APIQuestions = serde_json::from_str(&(user_main.client.get(format!("https://api.stackexchange.com/2.3/questions/{}?site=languagedesign.meta&key={}&filter=!)Q.zNIhl_-qxryCqQj5aX(Sp", question.id, config.get_api_key())).send().await.unwrap().error_for_status().unwrap().text().await.unwrap())).unwrap();
-    
-                                                if response.items.is_empty() {
-                                                    println!("watch_{}: question {}: seems to be deleted now", id, question.id);
-
-                                                    return;
-                                                }
-                                            }
-                                            
-                                            post(146046, format!("https://languagedesign.meta.stackexchange.com/q/{}", question.id), Arc::clone(&user_main)).await;
-                                            
-                                            println!("watch_{}: posted question {}", id, question.id);
-                                        }
-                                    }
-                                    _ => panic!("Unknown data.action: {}", data.action)
-                                }
-                            });
-                        }
-                    }
+        let saved = SavedFeeds {
+            revision: TMP_FILE_REVISION.to_string(),
+            last_seen: last_seen.clone()
+        };
+
+        let result: Result<()> = async {
+            tokio::fs::create_dir_all(self.config.get_state_dir()).await?;
+            tokio::fs::write(self.config.get_state_dir().join(FEEDS_FILE), serde_json::to_string(&saved)?).await?;
+
+            Ok(())
+        }.await;
+
+        if let Err(err) = result {
+            warn!(%err, "failed to save feeds");
+        }
+    }
+
+    /// Logs any configured site the API doesn't know about, or knows under a different URL.
+    pub async fn check_sites(&self) {
+        let mut known = HashMap::new();
+        let mut page = 1;
+
+        loop {
+            let sites = match self.api.sites(&self.api_client, Priority::Optional, page, 100).await {
+                Ok(sites) => sites,
+                Err(err) => {
+                    warn!(%err, "couldn't check sites");
+
+                    return;
                 }
+            };
+
+            known.extend(sites.items.into_iter().map(|site| (site.api_site_parameter, site.site_url)));
+
+            if !sites.has_more {
+                break;
+            }
+
+            page += 1;
+        }
+
+        for site in self.config.get_sites().values() {
+            match known.get(&site.id) {
+                None => warn!(site = %site.id, "site isn't on the API"),
+                Some(url) if url.trim_end_matches('/') != site.url.trim_end_matches('/') => warn!(site = %site.id, api_url = %url, configured_url = %site.url, "site is at a different URL on the API"),
                 _ => ()
             }
         }
-    });
-    
-    tokio::select!(
-        _ = duration => {
-            println!("watch_{}: close (alive over {} mins)", id, if kill_offset { 12 } else { 24 });
-            
-            watch.abort();
+    }
+
+    pub async fn first_deliveries(&self) -> BTreeMap<String, BTreeMap<Source, u64>> {
+        self.first_deliveries.lock().await.clone()
+    }
+
+    /// Pauses or resumes a route, returning whether it was paused before.
+    pub async fn set_paused(&self, route_id: &str, paused: bool) -> bool {
+        let mut routes = self.paused.lock().await;
+
+        let was_paused = routes.contains(route_id);
+
+        if paused {
+            routes.insert(route_id.to_owned());
+        } else {
+            routes.remove(route_id);
         }
-        watch_r = &mut watch => {
-            println!("watch_{}: close (stream closed)", id);
-            
-            watch_r.unwrap();
+
+        was_paused
+    }
+
+    pub async fn is_paused(&self, route_id: &str) -> bool {
+        self.paused.lock().await.contains(route_id)
+    }
+
+    /// Queues a message for a route's room, unless the route is paused.
+    async fn send(&self, route: &RouteConfig<'_>, text: String) {
+        if self.is_paused(route.id).await {
+            info!(route = route.id, %text, "paused, not sending");
+
+            return;
         }
-    );
-    
-    Ok(())
-}
 
-async fn post_from_api(down_since: u128, ids: Arc<Mutex<Ids>>, user_main: Arc<User>, user_sandbox: Arc<User>, config: Arc<Config>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let qs_200: APIQuestions = serde_json::from_str(&(user_main.client.get(format!("https://api.stackexchange.com/2.3/questions?pagesize=12&order=desc&sort=creation&site=codegolf&filter=!bBWABX77YE7)Qj&key={}", config.get_api_key())).send().await?.error_for_status()?.text().await?))?;
-    let qs_202: APIQuestions = serde_json::from_str(&(user_main.client.get(format!("https://api.stackexchange.com/2.3/questions?pagesize=12&order=desc&sort=creation&site=codegolf.meta&filter=!bBWABX77YE7)Qj&key={}", config.get_api_key())).send().await?.error_for_status()?.text().await?))?;
-    let as_sandbox: APIAnswers = serde_json::from_str(&(user_sandbox.client.get(format!("https://api.stackexchange.com/2.3/questions/2140/answers?pagesize=12&order=desc&sort=creation&site=codegolf.meta&filter=!-)QWsc3sXhrz&key={}", config.get_api_key())).send().await?.error_for_status()?.text().await?))?;
-    // PLDI
-    let qs_716: APIQuestions = serde_json::from_str(&(user_main.client.get(format!("https://api.stackexchange.com/2.3/questions?pagesize=12&order=desc&sort=creation&site=languagedesign&filter=!bBWABX77YE7)Qj&key={}", config.get_api_key())).send().await?.error_for_status()?.text().await?))?;
-    let qs_717: APIQuestions = serde_json::from_str(&(user_main.client.get(format!("https://api.stackexchange.com/2.3/questions?pagesize=12&order=desc&sort=creation&site=languagedesign.meta&filter=!bBWABX77YE7)Qj&key={}", config.get_api_key())).send().await?.error_for_status()?.text().await?))?;
-    
-    for q in qs_200.items {
-        if q.creation_date * 1000 > down_since - 20000 {
-            println!("api: qs_200: {}", q.question_id);
-            
-            if ids.lock().await.p_200.insert(q.question_id.to_string()) {
-                post(240, format!("https://codegolf.stackexchange.com/q/{}", q.question_id), Arc::clone(&user_main)).await;
+        self.queue.push(route.room.room_id(), route.user_id, Some(route.id), text, route.dry_run).await;
+    }
+
+    /// Queues a link to a post, unless the route is paused. `created` is when the post was made, if known.
+    async fn announce(&self, route: &RouteConfig<'_>, kind: LinkKind, post_id: &str, created: Option<u128>) {
+        if self.is_paused(route.id).await {
+            info!(route = route.id, post_id, "paused, not announcing");
+
+            return;
+        }
+
+        let url = route.site.page(&format!("/{}/{}", if kind == LinkKind::Question { "q" } else { "a" }, post_id));
+
+        self.queue.push_link(route.room.room_id(), route.user_id, route.id, kind, url, route.coalesce_window, created, route.dry_run).await;
+    }
+
+    fn post_url(route: &RouteConfig, question_id: &str, post_id: u64) -> String {
+        if post_id.to_string() == question_id {
+            route.site.page(&format!("/q/{}", post_id))
+        } else {
+            route.site.page(&format!("/a/{}", post_id))
+        }
+    }
+
+    async fn lookup(&self, route: &RouteConfig<'_>, kind: LinkKind, post_id: &str) -> Result<PostMeta> {
+        let (owner, creation_date) = match kind {
+            LinkKind::Question => self.api.questions(self.api_client(route), Priority::Essential, &route.site.id, &[post_id]).await?.items.into_iter().next().map(|question| (question.owner, question.creation_date)),
+            LinkKind::Answer => self.api.answers(self.api_client(route), Priority::Essential, &route.site.id, &[post_id]).await?.items.into_iter().next().map(|answer| (answer.owner, answer.creation_date))
+        }.ok_or(NotOnApi {})?;
+
+        Ok(PostMeta {
+            owner_reputation: owner.and_then(|owner| owner.reputation),
+            created: creation_date as u128 * 1000
+        })
+    }
+
+    /// Polls the API for a post according to the `waitForApi` policy, returning `None` if it never showed up.
+    async fn wait_for_api(&self, route: &RouteConfig<'_>, kind: LinkKind, post_id: &str) -> Option<PostMeta> {
+        let policy = self.config.get_wait_for_api();
+
+        let start = time();
+        let mut delay = Duration::from_millis(policy.initial_delay_ms);
+        let mut found = None;
+
+        for attempt in 1..=policy.attempts {
+            match self.lookup(route, kind, post_id).await {
+                Ok(meta) => {
+                    found = Some(meta);
+
+                    break;
+                }
+                Err(err) if !err.is::<NotOnApi>() => warn!(post_id, attempt, %err, "API lookup failed"),
+                Err(_) => ()
             }
+
+            if attempt == policy.attempts || time() - start + delay.as_millis() > policy.deadline_ms as u128 {
+                break;
+            }
+
+            tokio::time::sleep(delay).await;
+
+            delay = (delay * 2).min(Duration::from_millis(policy.max_delay_ms));
         }
+
+        let took = time() - start;
+
+        metrics::observe("npsp_wait_for_api_seconds", &[("outcome", if found.is_some() { "found" } else { "missing" })], took as f64 / 1000.0);
+
+        let mut latency = self.api_latency.lock().await;
+
+        if found.is_some() {
+            latency.found += 1;
+            latency.total_ms += took;
+            latency.max_ms = latency.max_ms.max(took);
+
+            info!(post_id, took_ms = took as u64, mean_ms = (latency.total_ms / latency.found as u128) as u64, max_ms = latency.max_ms as u64, "post showed up on the API");
+        } else {
+            latency.missing += 1;
+
+            warn!(post_id, took_ms = took as u64, missing = latency.missing, "post never showed up on the API");
+        }
+
+        found
     }
-    
-    for q in qs_202.items {
-        if q.creation_date * 1000 > down_since - 20000 {
-            println!("api: qs_202: {}", q.question_id);
-            
-            if ids.lock().await.p_202.insert(q.question_id.to_string()) {
-                post(240, format!("https://codegolf.meta.stackexchange.com/q/{}", q.question_id), Arc::clone(&user_main)).await;
+
+    /// Announces a post from the socket once it's on the API, holding back questions from low-reputation users. What
+    /// happens to posts that never show up is up to the `waitForApi` policy.
+    async fn announce_when_on_api(&self, route_id: &str, kind: LinkKind, post_id: &str, retried: bool) -> Result<()> {
+        let route = self.config.get_route_config(route_id).unwrap();
+
+        let created = match self.wait_for_api(&route, kind, post_id).await {
+            Some(meta) => {
+                let rep = meta.owner_reputation.unwrap_or(0);
+
+                if kind == LinkKind::Question && rep < LOW_REP {
+                    info!(post_id, rep, "low-reputation user, holding");
+
+                    self.scheduler.schedule(Task::new(time() + LOW_REP_HOLD.as_millis(), route_id, kind, post_id, Action::Recheck)).await;
+
+                    return Ok(());
+                }
+
+                Some(meta.created)
+            }
+            None => match self.config.get_wait_for_api().on_missing {
+                MissingPost::Post => {
+                    info!(post_id, "posting without metadata");
+
+                    None
+                }
+                MissingPost::RetryLater if !retried => {
+                    let delay = self.config.get_wait_for_api().retry_later_ms as u128;
+
+                    self.scheduler.schedule(Task::new(time() + delay, route_id, kind, post_id, Action::Retry)).await;
+
+                    return Ok(());
+                }
+                MissingPost::Drop | MissingPost::RetryLater => {
+                    warn!(post_id, "dropping");
+
+                    return Ok(());
+                }
             }
+        };
+
+        self.announce(&route, kind, post_id, created).await;
+
+        // Only once it's posted, and by when the post was made rather than when it arrived, so a restart in between
+        // still catches up on it
+        if let Some(created) = created {
+            self.mark_seen(route.watch_socket_id, created).await;
         }
+
+        info!(kind = kind.noun(1), post_id, "posted");
+
+        Ok(())
     }
-    
-    for a in as_sandbox.items {
-        if a.creation_date * 1000 > down_since - 20000 {
-            println!("api: as_sandbox: {}", a.answer_id);
-            
-            if ids.lock().await.p_202.insert(a.answer_id.to_string()) {
-                post(240, format!("https://codegolf.meta.stackexchange.com/a/{}", a.answer_id), Arc::clone(&user_sandbox)).await;
+
+    /// Marks the posts the scheduler is still holding as known, so catching up after a restart doesn't post them early.
+    pub async fn restore_scheduled(&self) {
+        for task in self.scheduler.pending().await {
+            if let Some(route) = self.config.get_route_config(&task.route_id) {
+                self.ids.lock().await.insert(route.room.room_id(), &route.site.id, &task.post_id);
             }
         }
     }
-    
-    // PLDI
-    
-    for q in qs_716.items {
-        if q.creation_date * 1000 > down_since - 20000 {
-            println!("api: qs_716: {}", q.question_id);
-            
-            if ids.lock().await.p_716.insert(q.question_id.to_string()) {
-                post(146046, format!("https://languagedesign.stackexchange.com/q/{}", q.question_id), Arc::clone(&user_main)).await;
+
+    /// Carries out a task the scheduler has found due.
+    #[instrument(name = "task", skip_all, fields(route = %task.route_id, post_id = %task.post_id, action = ?task.action))]
+    pub async fn run_scheduled(&self, task: &Task) -> Result<()> {
+        let route = match self.config.get_route_config(&task.route_id) {
+            Some(route) => route,
+            None => {
+                warn!("route no longer exists, dropping");
+
+                return Ok(());
             }
+        };
+
+        match task.action {
+            Action::Recheck => match self.lookup(&route, task.kind, &task.post_id).await {
+                Ok(meta) => {
+                    self.announce(&route, task.kind, &task.post_id, Some(meta.created)).await;
+
+                    self.mark_seen(route.watch_socket_id, meta.created).await;
+
+                    info!(kind = task.kind.noun(1), "posted");
+
+                    Ok(())
+                }
+                Err(err) if err.is::<NotOnApi>() => {
+                    info!(kind = task.kind.noun(1), "seems to be deleted now");
+
+                    Ok(())
+                }
+                Err(err) => Err(err)
+            },
+            Action::Retry => self.announce_when_on_api(&task.route_id, task.kind, &task.post_id, true).await
         }
     }
-    
-    for q in qs_717.items {
-        if q.creation_date * 1000 > down_since - 20000 {
-            println!("api: qs_717: {}", q.question_id);
-            
-            if ids.lock().await.p_717.insert(q.question_id.to_string()) {
-                post(146046, format!("https://languagedesign.meta.stackexchange.com/q/{}", q.question_id), Arc::clone(&user_main)).await;
+
+    async fn handle_action(self: Arc<Self>, action: String, payload: Payload) {
+        if self.shutdown.is_requested() {
+            return;
+        }
+
+        metrics::inc("npsp_socket_events_total", &[("action", &action)]);
+
+        let route_ids = match self.routes_by_action.get(&action) {
+            Some(route_ids) => route_ids.clone(),
+            None => {
+                warn!(%action, "no routes");
+
+                return;
+            }
+        };
+
+        for route_id in route_ids {
+            let span = tracing::info_span!("route", route = %route_id, site = %self.config.get_route_config(&route_id).unwrap().site.id);
+
+            let watcher = Arc::clone(&self);
+            let action = action.clone();
+            let payload = payload.clone();
+
+            tokio::spawn(async move {
+                if let Err(err) = watcher.handle_route(&route_id, &payload).await {
+                    warn!(%action, %err, "failed to handle");
+                }
+            }.instrument(span));
+        }
+    }
+
+    async fn handle_route(&self, route_id: &str, payload: &Payload) -> Result<()> {
+        let route = self.config.get_route_config(route_id).unwrap();
+
+        match (&route.watch_socket.config, payload) {
+            (WatchSocketConfigType::Questions | WatchSocketConfigType::Tag { .. }, Payload::NewestQuestion(question)) => {
+                let question_id = question.id.to_string();
+
+                info!(post_id = %question_id, title = question.title_encoded_fancy.as_deref().unwrap_or("untitled"), "new question");
+
+                if self.claim(&route, &question_id, Source::Socket).await {
+                    self.announce_when_on_api(route_id, LinkKind::Question, &question_id, false).await?;
+                }
+            }
+            (WatchSocketConfigType::Answers { .. }, Payload::Activity(activity)) => {
+                if let Activity::AnswerAdd { answer_id } = activity {
+                    let answer_id = answer_id.to_string();
+
+                    info!(post_id = %answer_id, "new answer");
+
+                    if self.claim(&route, &answer_id, Source::Socket).await {
+                        self.announce_when_on_api(route_id, LinkKind::Answer, &answer_id, false).await?;
+                    }
+                }
+            }
+            (WatchSocketConfigType::Active { tags }, Payload::ActiveQuestion(question)) => {
+                if question.site_base_host_address.as_deref() != Some(route.site.host()) || !(tags.is_empty() || question.tags.iter().any(|tag| tags.contains(tag))) {
+                    return Ok(());
+                }
+
+                let question_id = question.id.to_string();
+
+                if self.claim(&route, &question_id, Source::Socket).await {
+                    self.announce(&route, LinkKind::Question, &question_id, None).await;
+
+                    info!(post_id = %question_id, "posted active question");
+                }
+            }
+            (WatchSocketConfigType::QuestionActivity { question_id, events }, Payload::Activity(activity)) => {
+                if !events.contains(&activity.event()) {
+                    return Ok(());
+                }
+
+                let post = |post_id: &u64| Self::post_url(&route, question_id, *post_id);
+
+                let text = match activity {
+                    Activity::AnswerAdd { answer_id } => {
+                        if !self.claim(&route, &answer_id.to_string(), Source::Socket).await {
+                            return Ok(());
+                        }
+
+                        format!("[New answer]({}) on {}", route.site.page(&format!("/a/{}", answer_id)), route.site.page(&format!("/q/{}", question_id)))
+                    }
+                    Activity::PostEdit { post_id } => format!("{} was edited", post(post_id)),
+                    Activity::CommentAdd { post_id, comment_id } => {
+                        // Best effort; the comment may not be on the API yet
+                        let author = self.api.comments(self.api_client(&route), Priority::Optional, &route.site.id, &[comment_id]).await.ok()
+                            .and_then(|comments| comments.items.into_iter().next())
+                            .and_then(|comment| comment.owner)
+                            .and_then(|owner| owner.display_name);
+
+                        format!("[New comment]({}){} on {}", route.site.page(&format!("/posts/comments/{}", comment_id)), author.map_or(String::new(), |author| format!(" by {}", author)), post(post_id))
+                    }
+                    Activity::Score { post_id, score } => format!("{} is now at {}", post(post_id), score),
+                    Activity::Closed { post_id } => format!("{} was closed", post(post_id)),
+                    Activity::Reopened { post_id } => format!("{} was reopened", post(post_id))
+                };
+
+                info!(?activity, "question activity");
+
+                self.send(&route, text).await;
+            }
+            (_, payload) => debug!(?payload, "ignoring unexpected payload")
+        }
+
+        Ok(())
+    }
+
+    /// Posts anything the routes haven't seen since each watch socket was last processed, fetching each feed once.
+    pub async fn post_from_api(&self) -> Result<()> {
+        let mut watch_socket_ids = self.config.get_route_configs().values().map(|route| route.watch_socket_id.to_owned()).collect::<Vec<String>>();
+
+        watch_socket_ids.sort();
+        watch_socket_ids.dedup();
+
+        for watch_socket_id in watch_socket_ids {
+            self.catch_up(&watch_socket_id, Source::CatchUp).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn fetch_feed(&self, route: &RouteConfig<'_>, page: u32, priority: Priority) -> Result<Feed> {
+        Ok(match &route.watch_socket.config {
+            WatchSocketConfigType::Answers { question_id } | WatchSocketConfigType::QuestionActivity { question_id, .. } => {
+                let answers = self.api.newest_answers(self.api_client(route), priority, &route.site.id, question_id, page, PAGE_SIZE).await?;
+
+                Feed {
+                    kind: LinkKind::Answer,
+                    posts: answers.items.iter().map(|a| (a.answer_id, a.creation_date as u128)).collect(),
+                    has_more: answers.has_more,
+                    backoff: answers.backoff,
+                    quota_remaining: answers.quota_remaining
+                }
+            }
+            config => {
+                let tagged = match config {
+                    WatchSocketConfigType::Tag { tag } => Some(tag.as_str()),
+                    _ => None
+                };
+
+                let questions = self.api.newest_questions(self.api_client(route), priority, &route.site.id, tagged, page, PAGE_SIZE).await?;
+
+                Feed {
+                    kind: LinkKind::Question,
+                    posts: questions.items.iter().map(|q| (q.question_id, q.creation_date as u128)).collect(),
+                    has_more: questions.has_more,
+                    backoff: questions.backoff,
+                    quota_remaining: questions.quota_remaining
+                }
+            }
+        })
+    }
+
+    /// Where a route's feed can be browsed, for summaries of posts that were too old to announce.
+    fn feed_url(route: &RouteConfig) -> String {
+        match &route.watch_socket.config {
+            WatchSocketConfigType::Answers { question_id } | WatchSocketConfigType::QuestionActivity { question_id, .. } => route.site.page(&format!("/q/{}?answertab=newest", question_id)),
+            WatchSocketConfigType::Tag { tag } => route.site.page(&format!("/questions/tagged/{}?tab=Newest", tag)),
+            _ => route.site.page("/questions?tab=Newest")
+        }
+    }
+
+    /// Pages through a watch socket's feed back to the last post processed from it, announcing anything its routes
+    /// haven't seen. Posts older than the backfill limit are summarised or skipped instead. Returns `None` for feeds that
+    /// can't be fetched from the API.
+    pub async fn catch_up(&self, watch_socket_id: &str, source: Source) -> Result<Option<FeedPoll>> {
+        let routes = self.config.get_route_configs().into_values().filter(|route| route.watch_socket_id == watch_socket_id).collect::<Vec<RouteConfig>>();
+
+        let route = match routes.first() {
+            Some(route) if is_pollable(route.watch_socket) => route,
+            _ => return Ok(None)
+        };
+
+        let backfill = self.config.get_backfill();
+
+        let now = time();
+        let since = self.last_seen.lock().await.get(watch_socket_id).copied().unwrap_or(now - DEFAULT_CATCH_UP).saturating_sub(CATCH_UP_MARGIN);
+        let cutoff = since.max(now.saturating_sub(backfill.max_age_ms as u128));
+
+        let mut kind = LinkKind::Question;
+        let mut posts = Vec::new();
+        let mut truncated = false;
+        let (mut backoff, mut quota_remaining) = (None, None);
+
+        for page in 1..=MAX_PAGES {
+            if let Some(backoff) = backoff {
+                tokio::time::sleep(Duration::from_secs(backoff)).await;
+            }
+
+            let feed = self.fetch_feed(route, page, Priority::Essential).await?;
+
+            let reached_since = feed.posts.last().is_none_or(|(_, creation_date)| creation_date * 1000 <= since);
+
+            kind = feed.kind;
+            backoff = feed.backoff;
+            quota_remaining = feed.quota_remaining;
+
+            posts.extend(feed.posts.into_iter().filter(|(_, creation_date)| creation_date * 1000 > since));
+
+            if reached_since || !feed.has_more {
+                break;
+            }
+
+            truncated = page == MAX_PAGES;
+        }
+
+        let newest = posts.iter().map(|(_, creation_date)| creation_date * 1000).max();
+
+        // Oldest first, so they're announced in order
+        posts.reverse();
+
+        let (recent, old): (Vec<_>, Vec<_>) = posts.into_iter().partition(|(_, creation_date)| creation_date * 1000 > cutoff);
+
+        let mut new_posts = 0;
+
+        for route in &routes {
+            let mut missed = 0;
+
+            for (post_id, _) in &old {
+                if self.claim(route, &post_id.to_string(), source).await {
+                    missed += 1;
+                }
+            }
+
+            if missed > 0 {
+                warn!(watch_socket = watch_socket_id, route = route.id, missed, truncated, kind = kind.noun(missed), "posts older than the backfill limit");
+
+                if backfill.overflow == BackfillOverflow::Summarise {
+                    self.send(route, format!("{}{} older {} on {} were missed while offline: {}", missed, if truncated { "+" } else { "" }, kind.noun(missed), route.site.name, Self::feed_url(route))).await;
+                }
+            }
+
+            for (post_id, creation_date) in &recent {
+                if self.claim(route, &post_id.to_string(), source).await {
+                    info!(watch_socket = watch_socket_id, route = route.id, post_id, ?source, "caught up on post");
+
+                    self.announce(route, kind, &post_id.to_string(), Some(creation_date * 1000)).await;
+
+                    new_posts += 1;
+                }
             }
         }
+
+        if let Some(newest) = newest {
+            self.mark_seen(watch_socket_id, newest).await;
+        }
+
+        Ok(Some(FeedPoll {
+            new_posts,
+            backoff,
+            quota_remaining
+        }))
+    }
+
+    /// Compares a route's feed against the posts its room already knows about, returning the IDs of any created between
+    /// `since` and `until` that it missed; they're announced unless `report_only` is set. Returns `None` for feeds that
+    /// can't be fetched from the API.
+    pub async fn reconcile(&self, route_id: &str, since: u128, until: u128, report_only: bool) -> Result<Option<Vec<u64>>> {
+        let route = match self.config.get_route_config(route_id) {
+            Some(route) if is_pollable(route.watch_socket) => route,
+            _ => return Ok(None)
+        };
+
+        let feed = self.fetch_feed(&route, 1, Priority::Optional).await?;
+
+        let mut missed = Vec::new();
+
+        for (post_id, creation_date) in feed.posts {
+            if (since..until).contains(&(creation_date * 1000)) && self.claim(&route, &post_id.to_string(), Source::Reconcile).await {
+                if !report_only {
+                    self.announce(&route, feed.kind, &post_id.to_string(), Some(creation_date * 1000)).await;
+                }
+
+                missed.push(post_id);
+            }
+        }
+
+        Ok(Some(missed))
+    }
+
+    /// Handles a text frame from watch socket `replica`, unless another replica already has. Returns whether it was a
+    /// heartbeat, which needs answering.
+    pub async fn handle_frame(self: &Arc<Self>, replica: usize, string: String) -> bool {
+        let frame: Frame = match serde_json::from_str(&string) {
+            Ok(frame) => frame,
+            Err(err) => {
+                warn!(%err, "skipping bad frame");
+
+                return false;
+            }
+        };
+
+        let payload = socket::decode(&frame);
+
+        if !matches!(payload, Ok(Payload::Heartbeat)) {
+            if let Some(at) = self.last_event.lock().await.get_mut(&frame.action) {
+                *at = time();
+            }
+        }
+
+        match payload {
+            Ok(Payload::Heartbeat) => return true,
+            // Already delivered by another replica
+            Ok(_) if !self.rotation.first_delivery(string, replica).await => (),
+            Ok(payload) => {
+                tokio::spawn(Arc::clone(self).handle_action(frame.action, payload).in_current_span());
+            }
+            // Events on a question's feed that the bot doesn't know about are routine; anything else means the frame
+            // format has changed
+            Err(err @ DecodeError::UnknownEvent { .. }) => debug!(%err, "skipping frame"),
+            Err(err) => warn!(%err, "skipping frame")
+        }
+
+        false
+    }
+
+    async fn connect_watch_ws(self: &Arc<Self>, replica: usize, lifetime: Duration) -> Result<()> {
+        let actions = self.routes_by_action.keys().collect::<Vec<&String>>();
+
+        let mut subscription = Subscription::connect(&self.config.get_endpoints().socket_url, &actions).await?;
+
+        info!("open");
+
+        let duration = tokio::time::sleep(lifetime);
+
+        let mut watch = {
+            let watcher = Arc::clone(self);
+
+            tokio::spawn(async move {
+                loop {
+                    let string = match watcher.shutdown.until(subscription.next_text()).await {
+                        Some(Some(string)) => string?,
+                        Some(None) => break,
+                        None => {
+                            subscription.close().await?;
+
+                            break;
+                        }
+                    };
+
+                    capture::record(Event::WatchFrame { replica, frame: string.clone() });
+
+                    if watcher.handle_frame(replica, string).await {
+                        subscription.pong().await?;
+                    }
+                }
+
+                Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+            }.in_current_span())
+        };
+
+        let result = tokio::select!(
+            _ = duration => {
+                info!(lifetime_mins = lifetime.as_secs() / 60, "close, lifetime over");
+
+                metrics::inc("npsp_reconnects_total", &[("socket", "watch"), ("reason", "lifetime")]);
+
+                Ok(())
+            }
+            watch_r = &mut watch => {
+                let reason = match watch_r {
+                    Ok(Ok(())) if self.shutdown.is_requested() => "shutdown",
+                    Ok(Ok(())) => "closed",
+                    _ => "error"
+                };
+
+                info!(reason, "close, stream ended");
+
+                metrics::inc("npsp_reconnects_total", &[("socket", "watch"), ("reason", reason)]);
+
+                watch_r?
+            }
+        );
+
+        watch.abort();
+
+        result
     }
-    
-    Ok(())
 }
 
-pub async fn watch_ws(id: usize, ids: Arc<Mutex<Ids>>, users: [Arc<User>; 2], config: Arc<Config>) {
-    let mut first = true;
-    
-    loop {
-        post_from_api(time() - 1200000, Arc::clone(&ids), Arc::clone(&users[0]), Arc::clone(&users[1]), Arc::clone(&config)).await.unwrap();
+/// Keeps watch socket replica `id` connected until a fatal error, which is returned; transient failures are retried
+/// with backoff.
+#[instrument(name = "watch", skip_all, fields(replica = id))]
+pub async fn watch_ws(id: usize, watcher: Arc<Watcher>) -> Result<()> {
+    watcher.rotation.run(id, |lifetime| {
+        let watcher = Arc::clone(&watcher);
         
-        connect_watch_ws(id, Arc::clone(&ids), Arc::clone(&users[0]), Arc::clone(&users[1]), Arc::clone(&config), id == 1 && first).await.unwrap();
-        
-        first = false;
+        async move {
+            capture::record(Event::WatchConnecting);
 
-        tokio::time::sleep(Duration::from_millis(2000)).await;
-    }
+            watcher.post_from_api().await?;
+            
+            watcher.connect_watch_ws(id, lifetime).await
+        }
+    }).await
 }
@@ -0,0 +1,145 @@
+use std::time::{Duration, SystemTime};
+use serde_json::json;
+
+use mock_se::{MockSe, Question, Owner, Site};
+use npsp::{Options, Shutdown, config::UnlinkedConfig};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn config(mock: &MockSe, state_dir: &str) -> UnlinkedConfig {
+    serde_json::from_value(json!({
+        "apiKey": "",
+        "sites": {
+            "codegolf": {
+                "id": "codegolf",
+                "name": "Code Golf",
+                "url": mock.url(),
+                "websocketId": "200"
+            }
+        },
+        "users": {
+            "np": {
+                "email": "np@example.com",
+                "password": "hunter2"
+            }
+        },
+        "watchSockets": {
+            "codegolf-questions": {
+                "site": "codegolf",
+                "type": "questions"
+            }
+        },
+        "rooms": {
+            "tnb": {
+                "id": "240"
+            }
+        },
+        "routes": {
+            "codegolf": {
+                "user": "np",
+                "watchSocket": "codegolf-questions",
+                "room": "tnb"
+            }
+        },
+        "rotation": {
+            "watch": {
+                "replicas": 1,
+                "lifetimeMs": 3600000
+            },
+            "chat": {
+                "replicas": 1,
+                "lifetimeMs": 3600000
+            }
+        },
+        "endpoints": {
+            "siteUrl": mock.url(),
+            "chatServer": mock.url(),
+            "apiRoot": format!("{}/2.3", mock.url()),
+            "socketUrl": format!("{}/", mock.ws_url())
+        },
+        "stateDir": state_dir
+    })).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bot_announces_questions_and_acks_mentions() {
+    let mock = MockSe::start().await.unwrap();
+
+    mock.add_site(Site {
+        api_site_parameter: "codegolf".to_owned(),
+        name: "Code Golf".to_owned(),
+        site_url: mock.url()
+    });
+
+    let state_dir = std::env::temp_dir().join(format!("npsp-end-to-end-{}", std::process::id()));
+
+    let shutdown = Shutdown::new();
+
+    let bot = tokio::spawn(npsp::run(Options {
+        config: Some(config(&mock, state_dir.to_str().unwrap())),
+        shutdown: shutdown.clone(),
+        ..Options::default()
+    }));
+
+    assert!(mock.wait_for_subscribers("200-questions-newest", 1, TIMEOUT).await);
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+
+    mock.add_question(Question {
+        site: "codegolf".to_owned(),
+        question_id: 5,
+        creation_date: now,
+        title: "Golf".to_owned(),
+        tags: vec!["code-golf".to_owned()],
+        owner: Some(Owner {
+            user_id: 7,
+            display_name: "someone".to_owned(),
+            reputation: 50
+        })
+    });
+
+    mock.socket_send("200-questions-newest", &json!({ "id": "5", "titleEncodedFancy": "Golf" }));
+
+    assert_eq!(mock.wait_for_posts(240, 1, TIMEOUT).await, [format!("{}/q/5", mock.url())]);
+
+    assert!(mock.wait_for_chat_connections(240, 1, TIMEOUT).await);
+
+    let message_id = mock.chat_mention(240, 7, "someone", "@np hi");
+
+    assert!(mock.wait_for_ack(message_id, TIMEOUT).await);
+
+    shutdown.request();
+
+    assert!(tokio::time::timeout(TIMEOUT, bot).await.unwrap().unwrap().is_ok());
+
+    let _ = std::fs::remove_dir_all(state_dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dry_run_leaves_pending_mentions_unacked() {
+    let mock = MockSe::start().await.unwrap();
+
+    let state_dir = std::env::temp_dir().join(format!("npsp-end-to-end-dry-run-{}", std::process::id()));
+
+    mock.chat_mention(240, 7, "someone", "@np are you there?");
+
+    let shutdown = Shutdown::new();
+
+    let bot = tokio::spawn(npsp::run(Options {
+        config: Some(config(&mock, state_dir.to_str().unwrap())),
+        dry_run: true,
+        shutdown: shutdown.clone(),
+        ..Options::default()
+    }));
+
+    // Pending mentions are dealt with before the room's websocket is opened
+    assert!(mock.wait_for_chat_connections(240, 1, TIMEOUT).await);
+
+    shutdown.request();
+
+    assert!(tokio::time::timeout(TIMEOUT, bot).await.unwrap().unwrap().is_ok());
+
+    assert!(mock.acked().is_empty());
+
+    let _ = std::fs::remove_dir_all(state_dir);
+}
@@ -0,0 +1,92 @@
+use std::time::Duration;
+use futures::StreamExt;
+use serde_json::json;
+
+use mock_se::{MockSe, Question, Owner};
+use npsp::{login, chat::{self, PostOutcome}, socket::{Subscription, Payload}, api::{Api, Priority}, config::{ApiConfig, EndpointsConfig}};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn endpoints(mock: &MockSe) -> EndpointsConfig {
+    EndpointsConfig {
+        site_url: mock.url(),
+        chat_server: mock.url(),
+        api_root: format!("{}/2.3", mock.url()),
+        socket_url: format!("{}/", mock.ws_url())
+    }
+}
+
+#[tokio::test]
+async fn chat_client_posts_and_reads_rooms() {
+    let mock = MockSe::start().await.unwrap();
+
+    // The mock doesn't check fkeys, so there's no need to log in
+    let user = login::offline("np", &endpoints(&mock)).unwrap();
+
+    mock.chat_cooldowns(&[2]);
+
+    assert!(matches!(chat::post(240, "https://codegolf.stackexchange.com/q/1", &user).await, PostOutcome::Cooldown(2)));
+    assert!(matches!(chat::post(240, "https://codegolf.stackexchange.com/q/1", &user).await, PostOutcome::Sent));
+
+    let history = chat::fetch_events(240, 0, &user).await.unwrap();
+
+    assert_eq!(history.events.len(), 1);
+
+    let mut room = chat::connect(240, history.time, &user).await.unwrap();
+
+    // The connection is only registered once the handshake is through on the mock's side
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let message_id = mock.chat_mention(240, 7, "someone", "@np hi");
+
+    let frame = tokio::time::timeout(TIMEOUT, room.next()).await.unwrap().unwrap().unwrap();
+    let data = chat::decode_frame(frame.to_text().unwrap()).unwrap();
+
+    assert_eq!(data["r240"].e.as_ref().unwrap()[0].message_id, Some(message_id));
+
+    chat::ack(message_id, &user).await.unwrap();
+
+    assert_eq!(mock.acked(), [message_id]);
+}
+
+#[tokio::test]
+async fn subscription_answers_heartbeats_and_decodes_frames() {
+    let mock = MockSe::start().await.unwrap();
+
+    let mut subscription = Subscription::connect(&endpoints(&mock).socket_url, &["200-questions-newest"]).await.unwrap();
+
+    assert!(mock.wait_for_subscribers("200-questions-newest", 1, TIMEOUT).await);
+
+    mock.socket_heartbeat();
+    mock.socket_send("200-questions-newest", &json!({ "id": "5", "titleEncodedFancy": "Golf" }));
+
+    let (frame, payload) = tokio::time::timeout(TIMEOUT, subscription.next()).await.unwrap().unwrap().unwrap();
+
+    assert_eq!(frame.action, "200-questions-newest");
+    assert!(matches!(payload, Payload::NewestQuestion(question) if question.id == 5));
+}
+
+#[tokio::test]
+async fn api_client_looks_up_questions() {
+    let mock = MockSe::start().await.unwrap();
+
+    mock.add_question(Question {
+        site: "codegolf".to_owned(),
+        question_id: 1,
+        creation_date: 100,
+        title: "Golf".to_owned(),
+        tags: vec!["code-golf".to_owned()],
+        owner: Some(Owner {
+            user_id: 7,
+            display_name: "someone".to_owned(),
+            reputation: 50
+        })
+    });
+
+    let api = Api::new("", &ApiConfig::default(), &endpoints(&mock).api_root);
+
+    let questions = api.questions(&reqwest::Client::new(), Priority::Essential, "codegolf", &[1, 2]).await.unwrap();
+
+    assert_eq!(questions.items.len(), 1);
+    assert_eq!(questions.items[0].owner.as_ref().and_then(|owner| owner.reputation), Some(50));
+}